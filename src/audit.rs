@@ -0,0 +1,99 @@
+use std::fs::OpenOptions;
+use std::io::{Write, LineWriter};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::SystemTime;
+use time::OffsetDateTime;
+
+
+#[derive(Debug, Default)]
+pub enum AuditLog {
+    #[default]
+    Stderr,
+    // Lines are written by a dedicated thread so that slow disks never block request handling
+    File(Mutex<Sender<String>>)
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<AuditLog, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|error| format!("Cannot open {}: {}", path.display(), error))?;
+
+        let (sender, receiver) = channel::<String>();
+        thread::spawn(move || {
+            let mut writer = LineWriter::new(file);
+            for line in receiver {
+                if let Err(error) = writeln!(writer, "{}", line) {
+                    eprintln!("Cannot write to the audit log: {}", error);
+                }
+            }
+        });
+        Ok(AuditLog::File(Mutex::new(sender)))
+    }
+
+    pub fn login_attempt(
+        &self, username: Option<&str>, client_ip: Option<IpAddr>, request_id: Option<&str>, success: bool
+    ) {
+        self.write(login_event(unix_now(), username, client_ip, request_id, success));
+    }
+
+    fn write(&self, line: String) {
+        match self {
+            AuditLog::Stderr => eprintln!("{}", line),
+            AuditLog::File(sender) => {
+                sender.lock().unwrap().send(line).ok();
+            }
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() as i64
+}
+
+fn login_event(
+    time: i64, username: Option<&str>, client_ip: Option<IpAddr>, request_id: Option<&str>, success: bool
+) -> String {
+    // The username is quoted and escaped since it comes straight from the login form
+    let mut line = format!(
+        "{} login {} user={:?} client={}",
+        OffsetDateTime::from_unix_timestamp(time).format("%Y-%m-%dT%H:%M:%SZ"),
+        if success { "success" } else { "failure" },
+        username.unwrap_or(""),
+        client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string())
+    );
+    if let Some(request_id) = request_id {
+        line.push_str(&format!(" request={}", request_id));
+    }
+    line
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use super::login_event;
+
+    #[rstest(username, client_ip, request_id, success, expected,
+        case(Some("user"), Some("127.0.0.1"), None, true,
+             "2020-09-13T12:26:40Z login success user=\"user\" client=127.0.0.1"),
+        case(None, Some("::1"), None, false,
+             "2020-09-13T12:26:40Z login failure user=\"\" client=::1"),
+        case(Some("a\"b\nc"), None, None, false,
+             "2020-09-13T12:26:40Z login failure user=\"a\\\"b\\nc\" client=-"),
+        case(Some("user"), Some("127.0.0.1"), Some("0123456789abcdef"), true,
+             "2020-09-13T12:26:40Z login success user=\"user\" client=127.0.0.1 request=0123456789abcdef"),
+    )]
+    fn test_login_event(
+        username: Option<&str>, client_ip: Option<&str>, request_id: Option<&str>, success: bool, expected: &str
+    ) {
+        let client_ip = client_ip.map(|ip| ip.parse().unwrap());
+        assert_eq!(login_event(1600000000, username, client_ip, request_id, success), expected);
+    }
+}
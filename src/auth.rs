@@ -2,11 +2,23 @@ use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use generic_array::GenericArray;
 use generic_array::typenum::U32;
-use base64::{encode_config_buf, decode_config};
+use base64::{encode_config, encode_config_buf, decode_config};
 
 
 pub trait AuthConfig<'a> {
     fn secret(&'a self) -> &'a [u8; 32];
+
+    fn is_revoked(&'a self, _signature: &str) -> bool {
+        false
+    }
+
+    fn instance_id(&'a self) -> &'a str {
+        ""
+    }
+
+    fn grace_period(&'a self) -> u64 {
+        0
+    }
 }
 
 
@@ -14,32 +26,102 @@ fn sign_token<'a, T: AuthConfig<'a>>(bytes: &[u8], config: &'a T) -> GenericArra
     let mut hasher = Sha256::new();
     hasher.update(bytes);
     hasher.update(b".");
+    let instance_id = config.instance_id();
+    if !instance_id.is_empty() {
+        hasher.update(instance_id);
+        hasher.update(b".");
+    }
     hasher.update(&config.secret());
     return hasher.finalize();
 }
 
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Token {
     expiration: u64,
-    username: String
+    username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_expiration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roles: Vec<String>
 }
 
 #[derive(Debug, PartialEq)]
 pub enum VerificationError{
     FormatError,
     SignatureError,
-    ExpirationError
+    ExpirationError,
+    RevocationError,
+    // Expired, but still within the grace period, the session must be renewed
+    SoftExpired(Token)
+}
+
+pub fn token_signature(value: &str) -> Option<&str> {
+    value.find('.').map(|pos| &value[pos + 1..])
+}
+
+pub fn csrf_token<'a, T: AuthConfig<'a>>(session: &str, config: &'a T) -> String {
+    // Bound to the session cookie, base64 never contains ':' so it cannot match a token's signature
+    let signature = sign_token(format!("csrf:{}", session).as_bytes(), config);
+    encode_config(signature, base64::Config::new(base64::CharacterSet::UrlSafe, false))
+}
+
+pub fn login_csrf_token<'a, T: AuthConfig<'a>>(nonce: &str, expiration: u64, config: &'a T) -> String {
+    let payload = format!("{}.{}", expiration, nonce);
+    let signature = sign_token(format!("login-csrf:{}", payload).as_bytes(), config);
+    format!("{}.{}", payload, encode_config(signature, base64::Config::new(base64::CharacterSet::UrlSafe, false)))
+}
+
+pub fn verify_login_csrf_token<'a, T: AuthConfig<'a>>(value: &str, config: &'a T, time: u64) -> bool {
+    let mut parts = value.splitn(3, '.');
+    let (expiration, nonce) = match (parts.next().map(str::parse::<u64>), parts.next()) {
+        (Some(Ok(expiration)), Some(nonce)) => (expiration, nonce),
+        _ => return false
+    };
+    expiration >= time && login_csrf_token(nonce, expiration, config) == value
 }
 
 impl Token {
     pub fn new(expiration: u64, username: String) -> Token {
         Token{
             expiration: expiration,
-            username: username
+            username: username,
+            max_expiration: None,
+            roles: Vec::new()
         }
     }
 
+    pub fn with_max_expiration(mut self, max_expiration: u64) -> Token {
+        self.max_expiration = Some(max_expiration);
+        self
+    }
+
+    pub fn with_roles(mut self, roles: Vec<String>) -> Token {
+        self.roles = roles;
+        self
+    }
+
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn expiration(&self) -> u64 {
+        self.expiration
+    }
+
+    pub fn max_expiration(&self) -> u64 {
+        // Tokens without one were issued for the whole session, so they end at their expiration
+        self.max_expiration.unwrap_or(self.expiration)
+    }
+
+    pub fn has_max_expiration(&self) -> bool {
+        self.max_expiration.is_some()
+    }
+
     pub fn generate<'a, T: AuthConfig<'a>>(&self, config: &'a T) -> String {
         let json = serde_json::to_string(self).unwrap().into_bytes();
         let signature = sign_token(&json, config);
@@ -53,8 +135,8 @@ impl Token {
         result
     }
 
-    pub fn verify<'a, T: AuthConfig<'a>>(value: &str, config: &'a T, time: u64) ->
-            Result<String, VerificationError> {
+    pub fn decode<'a, T: AuthConfig<'a>>(value: &str, config: &'a T, time: u64) ->
+            Result<Token, VerificationError> {
         let b64_config = base64::Config::new(base64::CharacterSet::Standard, false);
 
         let pos = match value.find('.') {
@@ -75,11 +157,16 @@ impl Token {
         if signature[..] != sign_token(&token, config)[..] {
             return Err(VerificationError::SignatureError);
         }
+        if config.is_revoked(&value[pos + 1..]) {
+            return Err(VerificationError::RevocationError);
+        }
 
         match String::from_utf8(token) {
             Ok(token_json) => match serde_json::from_str::<Token>(&token_json) {
                 Ok(value) => if value.expiration > time {
-                    Ok(value.username)
+                    Ok(value)
+                } else if value.expiration.saturating_add(config.grace_period()) > time {
+                    Err(VerificationError::SoftExpired(value))
                 } else {
                     Err(VerificationError::ExpirationError)
                 },
@@ -95,18 +182,28 @@ impl Token {
 #[cfg(test)]
 pub mod tests {
     use hex_literal::hex;
-    use super::sign_token;
+    use super::{sign_token, csrf_token, login_csrf_token, verify_login_csrf_token};
     use super::AuthConfig;
     use super::Token;
     use super::VerificationError;
 
     pub struct MockConfig {
-        secret: [u8; 32]
+        secret: [u8; 32],
+        instance_id: String,
+        grace_period: u64
     }
 
     impl MockConfig {
         pub fn new(secret: [u8; 32]) -> MockConfig {
-            MockConfig{ secret: secret }
+            MockConfig{ secret: secret, instance_id: String::new(), grace_period: 0 }
+        }
+
+        pub fn with_instance_id(secret: [u8; 32], instance_id: &str) -> MockConfig {
+            MockConfig{ secret, instance_id: instance_id.to_string(), grace_period: 0 }
+        }
+
+        pub fn with_grace_period(secret: [u8; 32], grace_period: u64) -> MockConfig {
+            MockConfig{ secret, instance_id: String::new(), grace_period }
         }
     }
 
@@ -114,6 +211,14 @@ pub mod tests {
         fn secret(&'a self) -> &'a [u8;32] {
             &self.secret
         }
+
+        fn instance_id(&'a self) -> &'a str {
+            &self.instance_id
+        }
+
+        fn grace_period(&'a self) -> u64 {
+            self.grace_period
+        }
     }
 
     #[test]
@@ -126,6 +231,34 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_signing_tokens_with_instance_id() {
+        let secret = *b"01234567890123456789012345678901";
+        let default = sign_token(b"Hello, world", &MockConfig::with_instance_id(secret, ""));
+        let first = sign_token(b"Hello, world", &MockConfig::with_instance_id(secret, "first"));
+        let second = sign_token(b"Hello, world", &MockConfig::with_instance_id(secret, "second"));
+        assert_eq!(
+            default[..],
+            hex!("e6a9533b030dba663945657efd8f2f47f5920d24ee5c74e275c3856711a1544f")[..]
+        );
+        assert_ne!(first, default);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_tokens_do_not_cross_instances() {
+        let secret = *b"01234567890123456789012345678901";
+        let first = &MockConfig::with_instance_id(secret, "first");
+        let second = &MockConfig::with_instance_id(secret, "second");
+        let token = Token::new(10203040, String::from("user")).generate(first);
+        assert!(Token::decode(&token, first, 10203030).is_ok());
+        assert_eq!(Token::decode(&token, second, 10203030), Err(VerificationError::SignatureError));
+        assert_eq!(
+            Token::decode(&token, &MockConfig::new(secret), 10203030),
+            Err(VerificationError::SignatureError)
+        );
+    }
+
     #[test]
     fn test_generating_token() {
         let config = &MockConfig::new(*b"01234567890123456789012345678901");
@@ -136,9 +269,50 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_csrf_token() {
+        let config = &MockConfig::new(*b"01234567890123456789012345678901");
+        let token = Token::new(10203040, String::from("user")).generate(config);
+        let other = Token::new(10203041, String::from("user")).generate(config);
+        assert_eq!(csrf_token(&token, config), csrf_token(&token, config));
+        assert_ne!(csrf_token(&token, config), csrf_token(&other, config));
+        assert_ne!(
+            csrf_token(&token, config),
+            csrf_token(&token, &MockConfig::new(*b"10234567890123456789012345678901"))
+        );
+    }
+
+    #[test]
+    fn test_login_csrf_token() {
+        let config = &MockConfig::new(*b"01234567890123456789012345678901");
+        let token = login_csrf_token("nonce", 10203040, config);
+        assert!(verify_login_csrf_token(&token, config, 10203040));
+        assert!(!verify_login_csrf_token(&token, config, 10203041));
+        assert!(!verify_login_csrf_token(&token, &MockConfig::new(*b"10234567890123456789012345678901"), 10203030));
+        assert!(!verify_login_csrf_token(&token.replace("nonce", "other"), config, 10203030));
+        assert!(!verify_login_csrf_token(&token.replace("10203040", "10203050"), config, 10203030));
+        assert!(!verify_login_csrf_token("", config, 10203030));
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let config = &MockConfig::with_grace_period(*b"01234567890123456789012345678901", 60);
+        let token = Token::new(10203040, String::from("user")).generate(config);
+        assert!(Token::decode(&token, config, 10203039).is_ok());
+        assert_eq!(
+            Token::decode(&token, config, 10203040),
+            Err(VerificationError::SoftExpired(Token::new(10203040, String::from("user"))))
+        );
+        assert_eq!(
+            Token::decode(&token, config, 10203099),
+            Err(VerificationError::SoftExpired(Token::new(10203040, String::from("user"))))
+        );
+        assert_eq!(Token::decode(&token, config, 10203100), Err(VerificationError::ExpirationError));
+    }
+
     fn call_verify(token: &str, time: u64) -> Result<String, VerificationError> {
         let config = &MockConfig::new(*b"01234567890123456789012345678901");
-        Token::verify(token, config, time)
+        Token::decode(token, config, time).map(|token| token.username)
     }
 
     #[test]
@@ -189,6 +363,27 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_max_expiration_round_trip() {
+        let config = &MockConfig::new(*b"01234567890123456789012345678901");
+        let token = Token::new(10203040, String::from("user")).with_max_expiration(10300000);
+        let decoded = Token::decode(&token.generate(config), config, 10203030).unwrap();
+        assert_eq!(decoded, token);
+        assert_eq!(decoded.max_expiration(), 10300000);
+        assert_eq!(Token::new(10203040, String::from("user")).max_expiration(), 10203040);
+        assert!(decoded.has_max_expiration());
+        assert!(!Token::new(10203040, String::from("user")).has_max_expiration());
+    }
+
+    #[test]
+    fn test_roles_round_trip() {
+        let config = &MockConfig::new(*b"01234567890123456789012345678901");
+        let token = Token::new(10203040, String::from("user"))
+            .with_roles(vec!["editor".to_string(), "admin".to_string()]);
+        let decoded = Token::decode(&token.generate(config), config, 10203030).unwrap();
+        assert_eq!(decoded.roles(), &["editor".to_string(), "admin".to_string()]);
+    }
+
     #[test]
     fn test_valid_token() {
         assert_eq!(
@@ -0,0 +1,209 @@
+use std::io::{self, Write};
+use hyper::{Response, Body, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, VARY};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+
+// Smaller bodies fit in a single packet anyway, compressing them only adds overhead
+const MIN_COMPRESSED_SIZE: usize = 256;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip"
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(
+                    Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW
+                );
+                encoder.write_all(data)?;
+                Ok(encoder.into_inner())
+            },
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+pub fn parse_encoding(value: &str) -> Result<Encoding, String> {
+    match value.trim().to_lowercase().as_str() {
+        "br" | "brotli" => Ok(Encoding::Brotli),
+        "gzip" => Ok(Encoding::Gzip),
+        _ => Err(format!("Unsupported compression algorithm: {}", value.trim()))
+    }
+}
+
+fn quality(value: &str) -> Option<f32> {
+    // Format: <coding>[;q=<weight>], a missing weight means 1
+    let mut parts = value.split(';');
+    parts.next();
+    match parts.map(str::trim).find(|param| param.starts_with("q=") || param.starts_with("Q=")) {
+        Some(param) => param[2..].trim().parse::<f32>().ok(),
+        None => Some(1.0)
+    }
+}
+
+pub fn negotiate(accept_encoding: &str, algorithms: &[Encoding]) -> Option<Encoding> {
+    // The client's weights win, ties are broken by the order of the configured algorithms
+    let weight_of = |name: &str| accept_encoding.split(',')
+        .filter(|value| value.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case(name))
+        .filter_map(quality)
+        .reduce(f32::max);
+    // An explicitly listed coding overrides the "*" wildcard
+    let weight = |encoding: Encoding| weight_of(encoding.name()).or_else(|| weight_of("*"));
+
+    let mut chosen: Option<(Encoding, f32)> = None;
+    for &encoding in algorithms {
+        match weight(encoding) {
+            Some(q) if q > 0.0 && chosen.is_none_or(|(_, best)| q > best) => chosen = Some((encoding, q)),
+            _ => continue
+        }
+    }
+    chosen.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible_type(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+    mime.starts_with("text/") || mime.ends_with("+json") || mime.ends_with("+xml") || matches!(
+        mime.as_str(),
+        "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+    )
+}
+
+pub fn is_compressible(response: &Response<Body>) -> bool {
+    let headers = response.headers();
+    response.status() == StatusCode::OK
+        && !headers.contains_key(CONTENT_ENCODING)
+        && !headers.contains_key(CONTENT_RANGE)
+        && headers.get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(is_compressible_type)
+}
+
+pub async fn compress(response: &mut Response<Body>, encoding: Encoding) -> Result<(), io::Error> {
+    let body = std::mem::replace(response.body_mut(), Body::empty());
+    let data = hyper::body::to_bytes(body).await
+        .map_err(io::Error::other)?;
+    response.headers_mut().append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    if data.len() < MIN_COMPRESSED_SIZE {
+        *response.body_mut() = Body::from(data);
+        return Ok(());
+    }
+
+    let encoded = encoding.encode(&data)?;
+    response.headers_mut().insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+    response.headers_mut().insert(CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+    *response.body_mut() = Body::from(encoded);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use rstest::rstest;
+    use hyper::{Response, Body};
+    use flate2::read::GzDecoder;
+    use super::{negotiate, parse_encoding, is_compressible, compress, Encoding};
+
+    #[rstest(accept_encoding, expected,
+        case("br, gzip", Some(Encoding::Brotli)),
+        case("gzip, deflate, br", Some(Encoding::Brotli)),
+        case("gzip", Some(Encoding::Gzip)),
+        case("GZIP", Some(Encoding::Gzip)),
+        case("br;q=0.5, gzip", Some(Encoding::Gzip)),
+        case("br;q=0, gzip;q=0", None),
+        case("*", Some(Encoding::Brotli)),
+        case("*, br;q=0", Some(Encoding::Gzip)),
+        case("deflate", None),
+        case("", None),
+    )]
+    fn test_negotiate(accept_encoding: &str, expected: Option<Encoding>) {
+        assert_eq!(negotiate(accept_encoding, &[Encoding::Brotli, Encoding::Gzip]), expected);
+    }
+
+    #[rstest(accept_encoding, expected,
+        case("br, gzip", Some(Encoding::Gzip)),
+        case("br", None),
+    )]
+    fn test_negotiate_selected_algorithms(accept_encoding: &str, expected: Option<Encoding>) {
+        assert_eq!(negotiate(accept_encoding, &[Encoding::Gzip]), expected);
+    }
+
+    #[rstest(value, expected,
+        case("br", Ok(Encoding::Brotli)),
+        case(" Brotli ", Ok(Encoding::Brotli)),
+        case("gzip", Ok(Encoding::Gzip)),
+        case("deflate", Err("Unsupported compression algorithm: deflate".to_string())),
+    )]
+    fn test_parse_encoding(value: &str, expected: Result<Encoding, String>) {
+        assert_eq!(parse_encoding(value), expected);
+    }
+
+    #[rstest(status, content_type, content_encoding, expected,
+        case(200, Some("text/html; charset=utf-8"), None, true),
+        case(200, Some("application/json"), None, true),
+        case(200, Some("image/svg+xml"), None, true),
+        case(200, Some("image/png"), None, false),
+        case(200, None, None, false),
+        case(200, Some("text/html"), Some("gzip"), false),
+        case(304, Some("text/html"), None, false),
+    )]
+    fn test_is_compressible(status: u16, content_type: Option<&str>, content_encoding: Option<&str>, expected: bool) {
+        let mut response = Response::builder().status(status);
+        if let Some(content_type) = content_type {
+            response = response.header("Content-Type", content_type);
+        }
+        if let Some(content_encoding) = content_encoding {
+            response = response.header("Content-Encoding", content_encoding);
+        }
+        assert_eq!(is_compressible(&response.body(Body::empty()).unwrap()), expected);
+    }
+
+    #[rstest(encoding,
+        case(Encoding::Brotli),
+        case(Encoding::Gzip),
+    )]
+    #[tokio::test]
+    async fn test_compress(encoding: Encoding) {
+        let text = "Hello, world! ".repeat(100);
+        let mut response = Response::new(Body::from(text.clone()));
+        compress(&mut response, encoding).await.unwrap();
+        assert_eq!(response.headers().get("Content-Encoding").unwrap(), encoding.name());
+        assert_eq!(response.headers().get("Vary").unwrap(), "Accept-Encoding");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let mut decoded = String::new();
+        match encoding {
+            Encoding::Brotli => brotli::Decompressor::new(&body[..], 4096).read_to_string(&mut decoded),
+            Encoding::Gzip => GzDecoder::new(&body[..]).read_to_string(&mut decoded)
+        }.unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[tokio::test]
+    async fn test_small_body_uncompressed() {
+        let mut response = Response::new(Body::from("Hello"));
+        compress(&mut response, Encoding::Gzip).await.unwrap();
+        assert!(response.headers().get("Content-Encoding").is_none());
+        assert_eq!(hyper::body::to_bytes(response.into_body()).await.unwrap(), "Hello");
+    }
+}
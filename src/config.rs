@@ -1,85 +1,874 @@
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::Write;
+use std::env;
 use http::uri::Uri;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use clap::{ArgMatches};
+use percent_encoding::percent_decode_str;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
 use generic_array::{GenericArray, ArrayLength};
 use generic_array::typenum::U32;
 use crate::auth::AuthConfig;
-use crate::credentials::{UserCredentials, CredentialsStore};
+use crate::credentials::{UserCredentials, CredentialsStore, random_salt};
+use crate::proxy::{ProxyOptions, upstream_client};
+use crate::audit::AuditLog;
+use crate::service::STYLES;
+use crate::limit::{UserLimiter, RateLimiter};
+use crate::compression::{Encoding, parse_encoding};
 
 
+pub const MIN_SALT_LENGTH: usize = 5;
+pub const DEFAULT_MAX_URI_LENGTH: usize = 8 * 1024;
+pub const DEFAULT_RETRY_AFTER: u64 = 30;
+const DEFAULT_BASIC_AUTH_REALM: &str = "TiddlyProxy";
+pub const DEFAULT_MAX_USERS: usize = 10_000;
+
 #[derive(Debug)]
 pub struct ProxyConfig {
     remote_uri: Uri,
     secret: GenericArray<u8, U32>,
-    users: HashMap<Option<String>, UserCredentials>,
-    socker_addr: SocketAddr
+    users: RwLock<HashMap<Option<String>, UserCredentials>>,
+    users_file: Option<PathBuf>,
+    max_users: usize,
+    pepper: Option<String>,
+    case_insensitive_usernames: bool,
+    denied_users: Vec<String>,
+    instance_id: String,
+    revoked_tokens: Mutex<HashMap<String, u64>>,
+    maintenance: AtomicBool,
+    unavailable_upstreams: RwLock<HashSet<Uri>>,
+    redirect_status: StatusCode,
+    idle_timeout: Option<u64>,
+    grace_period: u64,
+    shutdown_timeout: Option<u64>,
+    basic_auth: bool,
+    basic_auth_realm: String,
+    trust_forwarded_proto: bool,
+    reject_path_traversal: bool,
+    private_assets: bool,
+    login_title: Option<String>,
+    login_logo_url: Option<String>,
+    login_message: Option<String>,
+    login_action: Option<String>,
+    cookie_domain: Option<String>,
+    allowed_paths: Option<Vec<String>>,
+    routes: Vec<(String, Uri)>,
+    canary_uri: Option<Uri>,
+    canary_header: Option<HeaderName>,
+    cors_origins: Vec<String>,
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+    audit_log: AuditLog,
+    socket_addrs: Vec<SocketAddr>,
+    http_redirect_port: Option<u16>,
+    https_port: u16,
+    max_connections: Option<usize>,
+    client_keepalive: bool,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<u64>,
+    user_limiter: UserLimiter,
+    rate_limiter: RateLimiter,
+    max_uri_length: usize,
+    warm_connections: usize,
+    proxy_options: ProxyOptions
 }
 
 impl ProxyConfig {
+    #[cfg(test)]
     pub fn from_values(
         wiki_url: &str, secret: &str, users: &str,
         host: Option<&str>, port: Option<&str>
+    ) -> Result<ProxyConfig, (&'static str, String)> {
+        ProxyConfig::from_values_with_max_users(wiki_url, secret, users, host, port, DEFAULT_MAX_USERS)
+    }
+
+    fn from_values_with_max_users(
+        wiki_url: &str, secret: &str, users: &str,
+        host: Option<&str>, port: Option<&str>, max_users: usize
     ) -> Result<ProxyConfig, (&'static str, String)> {
         let remote_uri = match parse_wiki_uri(wiki_url) {
             Ok(uri) => uri,
-            Err(error) => return Err(("wiki_url", error))
+            Err(error) => return Err(("wiki-url", error))
         };
 
         let secret = match parse_hex_string::<U32>(secret) {
             Ok(buffer) => buffer,
             Err(error) => return Err(("secret", error))
         };
+        if let Err(error) = validate_secret(&secret) {
+            return Err(("secret", error));
+        }
 
-        let users = match parse_credentials(users) {
-            Ok(users) => {
-                let mut map = HashMap::new();
-                for (username, credentials) in users {
-                    map.insert(username, credentials);
-                }
-                map
-            },
+        let users = parse_credentials_with_limit(users, max_users)
+            .and_then(|users| users_map(users, |name| name.to_string()));
+        let users = match users {
+            Ok(users) => users,
             Err(error) => return Err(("users", error))
         };
 
-        let port = match port.map(parse_port) {
-            Some(Ok(port)) => port,
+        let ports = match port.map(|ports| parse_list(ports, parse_port)) {
+            Some(Ok(ports)) => ports,
             Some(Err(error)) => return Err(("port", error)),
-            None => 3000
+            None => vec![3000]
         };
 
-        let host = match host.map(parse_host) {
-            Some(Ok(addr)) => addr,
+        let hosts = match host.map(|hosts| parse_list(hosts, parse_host)) {
+            Some(Ok(hosts)) => hosts,
             Some(Err(error)) => return Err(("host", error)),
-            None => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))
+            None => vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
         };
 
+        let mut socket_addrs = Vec::new();
+        for host in hosts.iter() {
+            for port in ports.iter() {
+                let addr = SocketAddr::new(*host, *port);
+                if !socket_addrs.contains(&addr) {
+                    socket_addrs.push(addr);
+                }
+            }
+        }
+
         Ok(ProxyConfig{
             remote_uri: remote_uri,
             secret: secret,
-            users: users,
-            socker_addr: SocketAddr::new(host, port)
+            users: RwLock::new(users),
+            users_file: None,
+            max_users,
+            pepper: None,
+            case_insensitive_usernames: false,
+            denied_users: Vec::new(),
+            instance_id: String::new(),
+            revoked_tokens: Mutex::new(HashMap::new()),
+            maintenance: AtomicBool::new(false),
+            unavailable_upstreams: RwLock::new(HashSet::new()),
+            redirect_status: StatusCode::SEE_OTHER,
+            idle_timeout: None,
+            grace_period: 0,
+            shutdown_timeout: None,
+            basic_auth: false,
+            basic_auth_realm: DEFAULT_BASIC_AUTH_REALM.to_string(),
+            trust_forwarded_proto: false,
+            reject_path_traversal: false,
+            private_assets: false,
+            login_title: None,
+            login_logo_url: None,
+            login_message: None,
+            login_action: None,
+            cookie_domain: None,
+            allowed_paths: None,
+            routes: Vec::new(),
+            canary_uri: None,
+            canary_header: None,
+            cors_origins: Vec::new(),
+            response_headers: Vec::new(),
+            audit_log: AuditLog::default(),
+            socket_addrs: socket_addrs,
+            http_redirect_port: None,
+            https_port: 443,
+            max_connections: None,
+            client_keepalive: true,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            user_limiter: UserLimiter::default(),
+            rate_limiter: RateLimiter::default(),
+            max_uri_length: DEFAULT_MAX_URI_LENGTH,
+            warm_connections: 0,
+            proxy_options: ProxyOptions{ login_path: "/".to_string(), ..ProxyOptions::default() }
         })
     }
 
     pub fn from_args<'a>(matches: &ArgMatches<'a>) -> Result<ProxyConfig, (&'static str, String)> {
-        ProxyConfig::from_values(
-            matches.value_of("wiki_url").unwrap(),
-            matches.value_of("secret").unwrap(),
-            matches.value_of("users").unwrap(),
-            matches.value_of("host"),
-            matches.value_of("port")
-        )
+        let users_file = matches.value_of("users-file").map(PathBuf::from);
+        let users = match &users_file {
+            Some(path) => match read_users_file(path) {
+                Ok(users) => users,
+                Err(error) => return Err(("users-file", error))
+            },
+            None => resolve_env(matches.value_of("users").unwrap()).map_err(|error| ("users", error))?
+        };
+        let max_users = optional_value(matches, "max-users", parse_limit)?;
+        let secret = match matches.value_of("secret-file") {
+            Some(path) => read_secret_file(Path::new(path), matches.is_present("strict-secret-file"))
+                .map_err(|error| ("secret-file", error))?,
+            None => resolve_env(matches.value_of("secret").unwrap()).map_err(|error| ("secret", error))?
+        };
+        let upstream_auth = optional_value(matches, "upstream-auth", parse_upstream_auth)?;
+
+        let hosts = matches.values_of("host").map(|hosts| hosts.collect::<Vec<&str>>().join(","));
+        let ports = matches.values_of("port").map(|ports| ports.collect::<Vec<&str>>().join(","));
+        // With routes, --wiki-url is optional and receives the requests no route matches
+        let mut routes = multiple_values(matches, "route", parse_route)?.unwrap_or_default();
+        let wiki_url = match matches.value_of("wiki-url") {
+            Some(wiki_url) => {
+                if !routes.is_empty() {
+                    routes.push((String::new(), parse_wiki_uri(wiki_url).map_err(|error| ("wiki-url", error))?));
+                }
+                wiki_url.to_string()
+            },
+            None => match routes.first() {
+                Some((_, uri)) => uri.to_string(),
+                None => return Err(("wiki-url", "The wiki URL is required".to_string()))
+            }
+        };
+        let config = ProxyConfig::from_values_with_max_users(
+            &wiki_url,
+            &secret,
+            &users,
+            hosts.as_deref(),
+            ports.as_deref(),
+            max_users.unwrap_or(DEFAULT_MAX_USERS)
+        )?;
+        let case_insensitive_usernames = matches.is_present("case-insensitive-usernames");
+        if case_insensitive_usernames {
+            // Usernames differing only in case would otherwise silently replace one another
+            let users = parse_credentials_with_limit(&users, max_users.unwrap_or(DEFAULT_MAX_USERS))
+                .map_err(|error| ("users", error))?;
+            users_map(users, |name| normalize_username(name, true)).map_err(|error| ("users", error))?;
+        }
+
+        let max_upload_size = optional_value(matches, "max-upload-size", parse_size)?;
+        let base_path = optional_value(matches, "base-path", parse_base_path)?;
+        let login_path = optional_value(matches, "login-path", parse_login_path)?;
+        let login_action = optional_value(matches, "login-action", parse_login_action)?;
+        // The logout page lives under the login path, a form posted there would log the user out instead
+        let logout_path = format!("{}/logout", login_path.as_deref().unwrap_or("/").trim_end_matches('/'));
+        if login_action.as_deref() == Some(logout_path.as_str()) {
+            return Err(("login-action", "Login action cannot be the logout path".to_string()));
+        }
+        let cookie_domain = optional_value(matches, "cookie-domain", parse_cookie_domain)?;
+        let audit_log = optional_value(matches, "audit-log", |path| AuditLog::open(Path::new(path)))?;
+        let idle_timeout = optional_value(matches, "idle-timeout", parse_duration)?;
+        let grace_period = optional_value(matches, "grace-period", parse_duration)?;
+        let shutdown_timeout = optional_value(matches, "shutdown-timeout", parse_duration)?;
+        let tcp_keepalive = optional_value(matches, "tcp-keepalive", parse_duration)?;
+        let redirect_status = optional_value(matches, "redirect-status", parse_redirect_status)?;
+        let upstream_retries = optional_value(matches, "upstream-retries", parse_retries)?;
+        let retry_after = optional_value(matches, "retry-after", parse_duration)?;
+        let max_connections = optional_value(matches, "max-connections", parse_limit)?;
+        let http_redirect_port = optional_value(matches, "http-redirect-port", parse_port)?;
+        let https_port = optional_value(matches, "https-port", parse_port)?;
+        if http_redirect_port.is_some_and(|port| config.socket_addrs.iter().any(|addr| addr.port() == port)) {
+            return Err(("http-redirect-port", "The port is already used by --port".to_string()));
+        }
+        let max_user_requests = optional_value(matches, "max-user-requests", parse_limit)?;
+        let user_rate = optional_value(matches, "user-rate", parse_rate)?;
+        let user_burst = optional_value(matches, "user-burst", parse_limit)?;
+        let login_cooldown = optional_value(matches, "login-cooldown", parse_duration)?;
+        let basic_auth_realm = optional_value(matches, "basic-auth-realm", parse_realm)?;
+        let max_uri_length = optional_value(matches, "max-uri-length", parse_size)?;
+        let warm_connections = optional_value(matches, "warm-connections", parse_warm_connections)?;
+        let pepper = optional_value(matches, "pepper", parse_pepper)?;
+        let canary_uri = optional_value(matches, "canary-url", parse_wiki_uri)?;
+        let canary_header = optional_value(matches, "canary-header", parse_header_name)?;
+        let compression = if matches.is_present("compress") {
+            multiple_values(matches, "compress-algorithms", parse_encoding)?
+                .unwrap_or_else(|| vec![Encoding::Brotli, Encoding::Gzip])
+        } else {
+            Vec::new()
+        };
+        Ok(config
+            .with_users_file(users_file)
+            .with_pepper(pepper)
+            .with_instance_id(matches.value_of("instance-id").unwrap_or_default().to_string())
+            .with_case_insensitive_usernames(case_insensitive_usernames)
+            .with_denied_users(multiple_values(matches, "deny-user", parse_denied_user)?.unwrap_or_default())
+            .with_max_connections(max_connections)
+            .with_http_redirect_port(http_redirect_port)
+            .with_https_port(https_port.unwrap_or(443))
+            .with_client_keepalive(!matches.is_present("no-client-keepalive"))
+            .with_tcp_options(matches.is_present("tcp-nodelay"), tcp_keepalive)
+            .with_max_user_requests(max_user_requests)
+            .with_user_rate(user_rate, user_burst)
+            .with_login_cooldown(login_cooldown)
+            .with_warm_connections(warm_connections.unwrap_or(0))
+            .with_max_uri_length(max_uri_length.map_or(DEFAULT_MAX_URI_LENGTH, |length| length as usize))
+            .with_redirect_status(redirect_status.unwrap_or(StatusCode::SEE_OTHER))
+            .with_idle_timeout(idle_timeout)
+            .with_grace_period(grace_period.unwrap_or(0))
+            .with_shutdown_timeout(shutdown_timeout)
+            .with_basic_auth(matches.is_present("basic-auth"))
+            .with_basic_auth_realm(basic_auth_realm.unwrap_or_else(|| DEFAULT_BASIC_AUTH_REALM.to_string()))
+            .with_trust_forwarded_proto(matches.is_present("trust-forwarded-proto"))
+            .with_reject_path_traversal(matches.is_present("reject-path-traversal"))
+            .with_private_assets(matches.is_present("private-assets"))
+            .with_login_title(matches.value_of("login-title").map(String::from))
+            .with_login_logo_url(matches.value_of("login-logo-url").map(String::from))
+            .with_login_message(matches.value_of("login-message").map(String::from))
+            .with_login_path(login_path.unwrap_or_else(|| "/".to_string()))
+            .with_login_action(login_action)
+            .with_cookie_domain(cookie_domain)
+            .with_audit_log(audit_log.unwrap_or_default())
+            .with_allowed_paths(multiple_values(matches, "allow-path", parse_allowed_path)?)
+            .with_routes(routes)
+            .with_canary(canary_uri, canary_header)
+            .with_cors_origins(multiple_values(matches, "cors-origin", parse_origin)?.unwrap_or_default())
+            .with_response_headers(multiple_values(
+                matches, "response-header", parse_response_header
+            )?.unwrap_or_default())
+            .with_base_path(base_path.unwrap_or_default())
+            .with_max_upload_size(max_upload_size)
+            .with_rewrite_cookies(matches.is_present("rewrite-cookies"))
+            .with_upstream_retries(upstream_retries.unwrap_or(0))
+            .with_retry_after(Some(retry_after.unwrap_or(DEFAULT_RETRY_AFTER)))
+            .with_offline_banner(matches.value_of("offline-banner").map(String::from))
+            .with_trusted_proxy(matches.is_present("trusted-proxy"))
+            .with_upstream_http2(matches.is_present("upstream-http2"))
+            .with_upstream_authorization(upstream_auth)
+            .with_decompress_upstream(matches.is_present("decompress-upstream"))
+            .with_compression(compression)
+            .with_strip_response_headers(multiple_values(
+                matches, "strip-response-headers", parse_header_name
+            )?.unwrap_or_default())
+            .with_allow_response_headers(multiple_values(
+                matches, "allow-response-headers", parse_header_name
+            )?)
+            .with_nosniff(matches.is_present("nosniff")))
+    }
+
+    pub fn with_users_file(mut self, users_file: Option<PathBuf>) -> ProxyConfig {
+        self.users_file = users_file;
+        self
+    }
+
+    pub fn with_pepper(mut self, pepper: Option<String>) -> ProxyConfig {
+        self.pepper = pepper;
+        self
+    }
+
+    pub fn with_case_insensitive_usernames(mut self, case_insensitive: bool) -> ProxyConfig {
+        self.case_insensitive_usernames = case_insensitive;
+        // Duplicates are rejected by from_args before the usernames are merged here
+        let users = self.users.get_mut().unwrap().drain()
+            .map(|(name, credentials)| (name.map(|name| normalize_username(&name, case_insensitive)), credentials))
+            .collect();
+        *self.users.get_mut().unwrap() = users;
+        self
+    }
+
+    pub fn with_denied_users(mut self, denied_users: Vec<String>) -> ProxyConfig {
+        self.denied_users = denied_users;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: Option<usize>) -> ProxyConfig {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn with_http_redirect_port(mut self, port: Option<u16>) -> ProxyConfig {
+        self.http_redirect_port = port;
+        self
+    }
+
+    pub fn with_https_port(mut self, port: u16) -> ProxyConfig {
+        self.https_port = port;
+        self
+    }
+
+    pub fn with_client_keepalive(mut self, client_keepalive: bool) -> ProxyConfig {
+        self.client_keepalive = client_keepalive;
+        self
+    }
+
+    pub fn with_user_rate(mut self, rate: Option<f64>, burst: Option<usize>) -> ProxyConfig {
+        // Without an explicit burst, a second's worth of requests may be made at once
+        self.rate_limiter = std::mem::take(&mut self.rate_limiter).with_rate(rate.map(|rate| {
+            let burst = burst.unwrap_or_else(|| rate.ceil() as usize).max(1);
+            (rate, burst.min(u32::MAX as usize) as u32)
+        }));
+        self
+    }
+
+    pub fn with_login_cooldown(mut self, max_delay: Option<u64>) -> ProxyConfig {
+        self.rate_limiter = std::mem::take(&mut self.rate_limiter)
+            .with_login_delay(max_delay.map(Duration::from_secs));
+        self
+    }
+
+    pub fn with_tcp_options(mut self, nodelay: bool, keepalive: Option<u64>) -> ProxyConfig {
+        self.tcp_nodelay = nodelay;
+        self.tcp_keepalive = keepalive;
+        self
+    }
+
+    pub fn with_max_user_requests(mut self, max_user_requests: Option<usize>) -> ProxyConfig {
+        self.user_limiter = UserLimiter::new(max_user_requests);
+        self
+    }
+
+    pub fn with_warm_connections(mut self, warm_connections: usize) -> ProxyConfig {
+        self.warm_connections = warm_connections;
+        self
+    }
+
+    pub fn with_max_uri_length(mut self, max_uri_length: usize) -> ProxyConfig {
+        self.max_uri_length = max_uri_length;
+        self
+    }
+
+    pub fn with_instance_id(mut self, instance_id: String) -> ProxyConfig {
+        self.instance_id = instance_id;
+        self
+    }
+
+    pub fn with_redirect_status(mut self, redirect_status: StatusCode) -> ProxyConfig {
+        self.redirect_status = redirect_status;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<u64>) -> ProxyConfig {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_grace_period(mut self, grace_period: u64) -> ProxyConfig {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn with_shutdown_timeout(mut self, shutdown_timeout: Option<u64>) -> ProxyConfig {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    pub fn with_basic_auth(mut self, basic_auth: bool) -> ProxyConfig {
+        self.basic_auth = basic_auth;
+        self
+    }
+
+    pub fn with_basic_auth_realm(mut self, realm: String) -> ProxyConfig {
+        self.basic_auth_realm = realm;
+        self
+    }
+
+    pub fn with_reject_path_traversal(mut self, reject_path_traversal: bool) -> ProxyConfig {
+        self.reject_path_traversal = reject_path_traversal;
+        self
+    }
+
+    pub fn with_private_assets(mut self, private_assets: bool) -> ProxyConfig {
+        self.private_assets = private_assets;
+        self.proxy_options.inline_styles = if private_assets { Some(STYLES) } else { None };
+        self
+    }
+
+    pub fn with_trust_forwarded_proto(mut self, trust_forwarded_proto: bool) -> ProxyConfig {
+        self.trust_forwarded_proto = trust_forwarded_proto;
+        self
+    }
+
+    pub fn with_login_title(mut self, title: Option<String>) -> ProxyConfig {
+        self.login_title = title;
+        self
+    }
+
+    pub fn with_login_logo_url(mut self, logo_url: Option<String>) -> ProxyConfig {
+        self.login_logo_url = logo_url;
+        self
+    }
+
+    pub fn with_login_message(mut self, message: Option<String>) -> ProxyConfig {
+        self.login_message = message;
+        self
+    }
+
+    pub fn with_login_path(mut self, login_path: String) -> ProxyConfig {
+        self.proxy_options.login_path = login_path;
+        self
+    }
+
+    pub fn with_login_action(mut self, login_action: Option<String>) -> ProxyConfig {
+        self.login_action = login_action;
+        self
+    }
+
+    pub fn with_cookie_domain(mut self, cookie_domain: Option<String>) -> ProxyConfig {
+        self.cookie_domain = cookie_domain;
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit_log: AuditLog) -> ProxyConfig {
+        self.audit_log = audit_log;
+        self
+    }
+
+    pub fn with_canary(mut self, canary_uri: Option<Uri>, canary_header: Option<HeaderName>) -> ProxyConfig {
+        self.canary_uri = canary_uri;
+        self.canary_header = canary_header;
+        self
+    }
+
+    pub fn with_routes(mut self, mut routes: Vec<(String, Uri)>) -> ProxyConfig {
+        // The longest matching prefix wins, so routes are tried from the longest one
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self.routes = routes;
+        self
+    }
+
+    pub fn with_allowed_paths(mut self, allowed_paths: Option<Vec<String>>) -> ProxyConfig {
+        self.allowed_paths = allowed_paths;
+        self
+    }
+
+    pub fn with_cors_origins(mut self, origins: Vec<String>) -> ProxyConfig {
+        self.cors_origins = origins;
+        self
+    }
+
+    pub fn with_response_headers(mut self, headers: Vec<(HeaderName, HeaderValue)>) -> ProxyConfig {
+        self.response_headers = headers;
+        self
+    }
+
+    pub fn with_max_upload_size(mut self, max_upload_size: Option<u64>) -> ProxyConfig {
+        self.proxy_options.max_upload_size = max_upload_size;
+        self
+    }
+
+    pub fn with_rewrite_cookies(mut self, rewrite_cookies: bool) -> ProxyConfig {
+        self.proxy_options.rewrite_cookies = rewrite_cookies;
+        self
+    }
+
+    pub fn with_upstream_retries(mut self, retries: u32) -> ProxyConfig {
+        self.proxy_options.upstream_retries = retries;
+        self
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Option<u64>) -> ProxyConfig {
+        self.proxy_options.retry_after = retry_after;
+        self
+    }
+
+    pub fn with_offline_banner(mut self, offline_banner: Option<String>) -> ProxyConfig {
+        self.proxy_options.offline_banner = offline_banner;
+        self
+    }
+
+    pub fn with_upstream_http2(mut self, upstream_http2: bool) -> ProxyConfig {
+        self.proxy_options.client = upstream_client(upstream_http2);
+        self
+    }
+
+    pub fn with_upstream_authorization(mut self, authorization: Option<HeaderValue>) -> ProxyConfig {
+        self.proxy_options.upstream_authorization = authorization;
+        self
+    }
+
+    pub fn with_decompress_upstream(mut self, decompress_upstream: bool) -> ProxyConfig {
+        self.proxy_options.decompress_upstream = decompress_upstream;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: Vec<Encoding>) -> ProxyConfig {
+        self.proxy_options.compression = compression;
+        self
+    }
+
+    pub fn with_trusted_proxy(mut self, trusted_proxy: bool) -> ProxyConfig {
+        self.proxy_options.trusted_proxy = trusted_proxy;
+        self
+    }
+
+    pub fn with_strip_response_headers(mut self, headers: Vec<HeaderName>) -> ProxyConfig {
+        self.proxy_options.strip_response_headers = headers;
+        self
+    }
+
+    pub fn with_allow_response_headers(mut self, headers: Option<Vec<HeaderName>>) -> ProxyConfig {
+        self.proxy_options.allow_response_headers = headers;
+        self
+    }
+
+    pub fn with_nosniff(mut self, nosniff: bool) -> ProxyConfig {
+        self.proxy_options.nosniff = nosniff;
+        self
+    }
+
+    pub fn with_base_path(mut self, base_path: String) -> ProxyConfig {
+        self.proxy_options.base_path = base_path;
+        self
     }
 
     pub fn remote_uri(&self) -> &Uri {
         &self.remote_uri
     }
 
-    pub fn socket_addr(&self) -> &SocketAddr {
-        &self.socker_addr
+    pub fn socket_addrs(&self) -> &[SocketAddr] {
+        &self.socket_addrs
+    }
+
+    pub fn https_port(&self) -> u16 {
+        self.https_port
+    }
+
+    pub fn http_redirect_addrs(&self) -> Vec<SocketAddr> {
+        // Listens on the same hosts as the proxy itself
+        let port = match self.http_redirect_port {
+            Some(port) => port,
+            None => return Vec::new()
+        };
+        let mut addrs = Vec::new();
+        for addr in self.socket_addrs.iter() {
+            let addr = SocketAddr::new(addr.ip(), port);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        addrs
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn client_keepalive(&self) -> bool {
+        self.client_keepalive
+    }
+
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay
+    }
+
+    pub fn tcp_keepalive(&self) -> Option<u64> {
+        self.tcp_keepalive
+    }
+
+    pub fn user_limiter(&self) -> &UserLimiter {
+        &self.user_limiter
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn warm_connections(&self) -> usize {
+        self.warm_connections
+    }
+
+    pub fn max_uri_length(&self) -> usize {
+        self.max_uri_length
+    }
+
+    pub fn proxy_options(&self) -> &ProxyOptions {
+        &self.proxy_options
+    }
+
+    pub fn redirect_status(&self) -> StatusCode {
+        self.redirect_status
+    }
+
+    pub fn idle_timeout(&self) -> Option<u64> {
+        self.idle_timeout
+    }
+
+    pub fn shutdown_timeout(&self) -> Option<u64> {
+        self.shutdown_timeout
+    }
+
+    pub fn basic_auth(&self) -> bool {
+        self.basic_auth
+    }
+
+    pub fn basic_auth_realm(&self) -> &str {
+        &self.basic_auth_realm
+    }
+
+    pub fn reject_path_traversal(&self) -> bool {
+        self.reject_path_traversal
+    }
+
+    pub fn private_assets(&self) -> bool {
+        self.private_assets
+    }
+
+    pub fn trust_forwarded_proto(&self) -> bool {
+        self.trust_forwarded_proto
+    }
+
+    pub fn login_title(&self) -> Option<&str> {
+        self.login_title.as_deref()
+    }
+
+    pub fn login_logo_url(&self) -> Option<&str> {
+        self.login_logo_url.as_deref()
+    }
+
+    pub fn login_message(&self) -> Option<&str> {
+        self.login_message.as_deref()
+    }
+
+    pub fn login_path(&self) -> &str {
+        &self.proxy_options.login_path
+    }
+
+    pub fn login_action(&self) -> Option<&str> {
+        self.login_action.as_deref()
+    }
+
+    pub fn cookie_domain(&self) -> Option<&str> {
+        self.cookie_domain.as_deref()
+    }
+
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    pub fn is_path_allowed(&self, path: &str) -> bool {
+        // A pattern matches the path exactly, a trailing '*' matches any path starting with the rest.
+        // Patterns see the decoded path, and dot-segments are refused since the wiki would resolve
+        // them to a path outside of the matched prefix
+        let patterns = match &self.allowed_paths {
+            Some(patterns) => patterns,
+            None => return true
+        };
+        let path = percent_decode_str(path).decode_utf8_lossy();
+        if path.split(['/', '\\']).any(|segment| segment == "." || segment == "..") {
+            return false;
+        }
+        patterns.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == pattern.as_str()
+        })
+    }
+
+    pub fn canary_uri(&self) -> Option<&Uri> {
+        self.canary_uri.as_ref()
+    }
+
+    pub fn upstreams(&self) -> Vec<&Uri> {
+        // Every wiki server a request can be sent to, each of them once
+        let mut upstreams: Vec<&Uri> = Vec::new();
+        let routed = self.routes.iter().map(|(_, uri)| uri);
+        let all = std::iter::once(&self.remote_uri).chain(routed).chain(self.canary_uri.as_ref());
+        for uri in all {
+            if !upstreams.contains(&uri) {
+                upstreams.push(uri);
+            }
+        }
+        upstreams
+    }
+
+    pub fn canary_header(&self) -> Option<&HeaderName> {
+        self.canary_header.as_ref()
+    }
+
+    pub fn routes(&self) -> &[(String, Uri)] {
+        &self.routes
+    }
+
+    pub fn upstream_for(&self, path: &str) -> Option<(&str, &Uri)> {
+        // Returns the matching prefix and the wiki the request is sent to
+        if self.routes.is_empty() {
+            return Some(("", &self.remote_uri));
+        }
+        self.routes.iter()
+            .find(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+                None => false
+            })
+            .map(|(prefix, uri)| (prefix.as_str(), uri))
+    }
+
+    pub fn cors_origins(&self) -> &[String] {
+        &self.cors_origins
+    }
+
+    pub fn response_headers(&self) -> &[(HeaderName, HeaderValue)] {
+        &self.response_headers
+    }
+
+    pub fn base_path(&self) -> &str {
+        &self.proxy_options.base_path
+    }
+
+    pub fn revoke_token(&self, signature: &str, expiration: u64) {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let mut revoked_tokens = self.revoked_tokens.lock().unwrap();
+        revoked_tokens.retain(|_, expiration| *expiration > now);
+        if expiration > now {
+            revoked_tokens.insert(signature.to_string(), expiration);
+        }
+    }
+
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, maintenance: bool) {
+        self.maintenance.store(maintenance, Ordering::Relaxed);
+    }
+
+    pub fn set_upstream_available(&self, uri: &Uri, available: bool) {
+        // Most requests do not change anything, those only need the read lock
+        if self.unavailable_upstreams.read().unwrap().contains(uri) == available {
+            let mut unavailable = self.unavailable_upstreams.write().unwrap();
+            if available {
+                unavailable.remove(uri);
+            } else {
+                unavailable.insert(uri.clone());
+            }
+        }
+    }
+
+    pub fn offline_banner(&self, uri: &Uri) -> Option<&str> {
+        // Shown only while the last attempt to reach this wiki has failed
+        if self.unavailable_upstreams.read().unwrap().contains(uri) {
+            self.proxy_options.offline_banner.as_deref()
+        } else {
+            None
+        }
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.read().unwrap().len()
+    }
+
+    pub fn users_file(&self) -> Option<&Path> {
+        self.users_file.as_deref()
+    }
+
+    pub fn normalize_username(&self, name: &str) -> String {
+        normalize_username(name, self.case_insensitive_usernames)
+    }
+
+    pub fn set_users(&self, users: Vec<(Option<String>, UserCredentials)>) -> Result<(), String> {
+        let users = users_map(users, |name| self.normalize_username(name))?;
+        *self.users.write().unwrap() = users;
+        Ok(())
+    }
+
+    pub fn change_password(&self, name: Option<&str>, password: &str) -> Result<(), String> {
+        // The lock is held while the file is written, so concurrent changes cannot interleave
+        let name = name.map(|name| self.normalize_username(name));
+        let mut users = self.users.write().unwrap();
+        let credentials = match users.get(&name) {
+            Some(credentials) => credentials.with_password(password, self.pepper.as_deref()),
+            None => return Err("Unknown user".to_string())
+        };
+        if let Some(path) = &self.users_file {
+            let line = credentials.to_credentials_string(name.as_deref());
+            update_users_file(path, name.as_deref(), &line, |name| self.normalize_username(name))?;
+        }
+        users.insert(name, credentials);
+        Ok(())
+    }
+
+    pub fn reload_users(&self) -> Result<usize, String> {
+        let path = match &self.users_file {
+            Some(path) => path,
+            None => return Err("Users were not loaded from a file".to_string())
+        };
+        let users = parse_credentials_with_limit(&read_users_file(path)?, self.max_users)?;
+        let count = users.len();
+        self.set_users(users)?;
+        Ok(count)
     }
 }
 
@@ -87,11 +876,39 @@ impl<'a> AuthConfig<'a> for ProxyConfig {
     fn secret(&'a self) -> &'a [u8;32] {
         self.secret.as_ref()
     }
+
+    fn is_revoked(&'a self, signature: &str) -> bool {
+        self.revoked_tokens.lock().unwrap().contains_key(signature)
+    }
+
+    fn instance_id(&'a self) -> &'a str {
+        &self.instance_id
+    }
+
+    fn grace_period(&'a self) -> u64 {
+        self.grace_period
+    }
 }
 
 impl CredentialsStore for ProxyConfig {
-    fn credentials_for<'a>(&'a self, name: Option<&str>) -> Option<&'a UserCredentials>{
-        self.users.get(&name.map(String::from))
+    fn credentials_for(&self, name: Option<&str>) -> Option<UserCredentials>{
+        let name = name.map(|name| self.normalize_username(name));
+        self.users.read().unwrap().get(&name).cloned()
+    }
+
+    fn pepper(&self) -> Option<&str> {
+        self.pepper.as_deref()
+    }
+
+    fn is_denied(&self, name: Option<&str>) -> bool {
+        if self.credentials_for(name).is_some_and(|credentials| credentials.is_denied()) {
+            return true;
+        }
+        let name = match name {
+            Some(name) => self.normalize_username(name),
+            None => return false
+        };
+        self.denied_users.iter().any(|denied| self.normalize_username(denied) == name)
     }
 }
 
@@ -109,6 +926,14 @@ impl<'a> AuthConfig<'a> for ArcAuthProxyConfig {
     fn secret(&'a self) -> &'a [u8; 32] {
         self.obj.secret()
     }
+
+    fn is_revoked(&'a self, signature: &str) -> bool {
+        self.obj.is_revoked(signature)
+    }
+
+    fn instance_id(&'a self) -> &'a str {
+        self.obj.instance_id()
+    }
 }
 
 pub fn parse_username(value: &str) -> Result<String, &'static str> {
@@ -123,6 +948,13 @@ pub fn parse_username(value: &str) -> Result<String, &'static str> {
     Ok(String::from(value))
 }
 
+fn parse_denied_user(value: &str) -> Result<String, String> {
+    match parse_username(value)? {
+        name if name.is_empty() => Err("A username cannot be empty".to_string()),
+        name => Ok(name)
+    }
+}
+
 pub fn parse_wiki_uri(uri: &str) -> Result<Uri, String> {
     match uri.parse::<Uri>() {
         Ok(uri) => {
@@ -136,14 +968,16 @@ pub fn parse_wiki_uri(uri: &str) -> Result<Uri, String> {
                 Some(authority) => authority.clone()
             };
 
-            if uri.query() != None {
-                return Err(String::from("URL cannot contain a query"));
-            }
+            // A fixed query is kept and merged with the query of every proxied request
+            let path_and_query = match uri.query() {
+                Some(query) if !query.is_empty() => format!("{}?{}", uri.path(), query),
+                _ => uri.path().to_string()
+            };
 
             Ok(Uri::builder()
                 .scheme("http")
                 .authority(authority)
-                .path_and_query(uri.path())
+                .path_and_query(path_and_query.as_str())
                 .build()
                 .unwrap())
         },
@@ -170,11 +1004,43 @@ fn parse_hex_string<N: ArrayLength<u8>>(value: &str) -> Result<GenericArray<u8,
     Ok(result)
 }
 
-fn parse_credentials_part(value: &str) -> Result<(Option<String>, UserCredentials), String> {
-    // Format: [<username>]:<salt>:<password>
-    let components: Vec<&str> = value.trim().split(":").collect();
-    if components.len() != 3 {
-        return Err("Wrong number of components".to_string())
+fn validate_secret(secret: &[u8]) -> Result<(), String> {
+    // A random 32-byte secret virtually always has far more distinct bytes than
+    // this, while placeholders such as "0000..." or "0101..." have only a few
+    const MIN_DISTINCT_BYTES: usize = 8;
+
+    let mut seen = [false; 256];
+    for byte in secret.iter() {
+        seen[*byte as usize] = true;
+    }
+    match seen.iter().filter(|seen| **seen).count() {
+        1 => Err("Secret cannot consist of a single repeated byte".to_string()),
+        count if count < MIN_DISTINCT_BYTES => Err("Secret is not random enough, use gensecret".to_string()),
+        _ => Ok(())
+    }
+}
+
+fn parse_credentials_part(value: &str) -> Result<(Option<String>, UserCredentials), String> {
+    // Format: [!][<username>]:<salt>:<password>[:<role>,<role>...], '!' suspends the user
+    let (denied, value) = match value.trim().strip_prefix('!') {
+        Some(value) => (true, value),
+        None => (false, value.trim())
+    };
+    let components: Vec<&str> = value.split(':').collect();
+    if components.len() < 3 {
+        return Err("Wrong number of components".to_string())
+    }
+
+    // The hash is the only component with a fixed format, so it is located first
+    // (searching from the right) and extra colons are reported for the field they are in
+    let hash_index = components.iter()
+        .rposition(|component| parse_hex_string::<U32>(component).is_ok())
+        .filter(|index| *index >= 2)
+        .unwrap_or(2);
+    if hash_index > 2 {
+        return Err("Either the username or the salt contains ':'".to_string());
+    } else if components.len() > hash_index + 2 {
+        return Err("The list of roles contains ':'".to_string());
     }
 
     let username = if components[0].len() > 0 {
@@ -183,22 +1049,48 @@ fn parse_credentials_part(value: &str) -> Result<(Option<String>, UserCredential
         None
     };
 
-    let salt = components[1];
-    if salt.len() < 5 {
+    let salt = components[hash_index - 1];
+    if salt.len() < MIN_SALT_LENGTH {
         return Err("The value for salt is too short".to_string());
     }
 
-    let password_hash = match parse_hex_string::<U32>(components[2]) {
+    let password_hash = match parse_hex_string::<U32>(components[hash_index]) {
         Ok(buffer) => buffer.into(),
         Err(message) => return Err(format!("Password hash is not valid ({})", message))
     };
 
-    Ok((username.map(String::from), UserCredentials::new(salt.to_string(), password_hash)))
+    let roles = match components.get(hash_index + 1) {
+        Some(roles) => roles.split(',')
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .map(String::from)
+            .collect(),
+        None => Vec::new()
+    };
+
+    Ok((
+        username.map(String::from),
+        UserCredentials::new(salt.to_string(), password_hash).with_roles(roles).with_denied(denied)
+    ))
+}
+
+pub fn parse_credentials(value: &str) -> Result<Vec<(Option<String>, UserCredentials)>, String> {
+    parse_credentials_with_limit(value, DEFAULT_MAX_USERS)
 }
 
-fn parse_credentials(value: &str) -> Result<Vec<(Option<String>, UserCredentials)>, String> {
+pub fn parse_credentials_with_limit(
+    value: &str, max_users: usize
+) -> Result<Vec<(Option<String>, UserCredentials)>, String> {
     let mut result = Vec::<(Option<String>, UserCredentials)>::new();
-    let parts: Vec<&str> = value.split(';').collect();
+    // Empty parts left by a trailing or doubled separator are skipped
+    let parts: Vec<&str> = value.split(';').filter(|part| !part.trim().is_empty()).collect();
+    if parts.is_empty() {
+        return Err("No credentials specified".to_string());
+    }
+    // Checked before parsing, so a huge malformed input is not hashed entry by entry
+    if parts.len() > max_users {
+        return Err(format!("Too many users: {} specified, at most {} are allowed", parts.len(), max_users));
+    }
     for part in parts.iter() {
         match parse_credentials_part(part) {
             Ok((username, credentials)) => {
@@ -213,6 +1105,122 @@ fn parse_credentials(value: &str) -> Result<Vec<(Option<String>, UserCredentials
     Ok(result)
 }
 
+fn resolve_env(value: &str) -> Result<String, String> {
+    // Values in form of "env:NAME" are read from the environment variable NAME
+    match value.strip_prefix("env:") {
+        Some(name) => env::var(name)
+            .map_err(|_| format!("Environment variable {} is not set", name)),
+        None => Ok(value.to_string())
+    }
+}
+
+fn normalize_username(name: &str, case_insensitive: bool) -> String {
+    if case_insensitive {
+        name.trim().to_lowercase()
+    } else {
+        name.to_string()
+    }
+}
+
+fn users_map<F>(
+    users: Vec<(Option<String>, UserCredentials)>, normalize: F
+) -> Result<HashMap<Option<String>, UserCredentials>, String> where F: Fn(&str) -> String {
+    let mut result = HashMap::new();
+    for (name, credentials) in users {
+        let name = name.map(|name| normalize(&name));
+        if let Some(name) = &name {
+            if result.contains_key(&Some(name.clone())) {
+                return Err(format!("Username {} is given more than once", name));
+            }
+        }
+        result.insert(name, credentials);
+    }
+    Ok(result)
+}
+
+fn read_users_file(path: &Path) -> Result<String, String> {
+    // One or more credentials per line, empty lines and lines starting with '#' are ignored
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Cannot read {}: {}", path.display(), error))?;
+    let lines: Vec<&str> = content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    Ok(lines.join(";"))
+}
+
+fn read_secret_file(path: &Path, strict: bool) -> Result<String, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Cannot read {}: {}", path.display(), error))?;
+    if is_readable_by_others(path)? {
+        let message = format!("{} is readable by other users, restrict it with chmod 600", path.display());
+        if strict {
+            return Err(message);
+        }
+        eprintln!("Warning: {}", message);
+    }
+    Ok(content.trim().to_string())
+}
+
+#[cfg(unix)]
+fn is_readable_by_others(path: &Path) -> Result<bool, String> {
+    use std::os::unix::fs::PermissionsExt;
+    let metadata = fs::metadata(path)
+        .map_err(|error| format!("Cannot read {}: {}", path.display(), error))?;
+    Ok(metadata.permissions().mode() & 0o044 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_readable_by_others(_path: &Path) -> Result<bool, String> {
+    Ok(false)
+}
+
+fn update_users_file<F>(path: &Path, name: Option<&str>, line: &str, normalize: F) -> Result<(), String>
+        where F: Fn(&str) -> String {
+    // Only the user's own entry is replaced, comments and other users are kept as they are
+    let content = fs::read_to_string(path)
+        .map_err(|error| format!("Cannot read {}: {}", path.display(), error))?;
+    let is_user = |part: &str| {
+        let username = part.trim().trim_start_matches('!').split(':').next().unwrap_or("");
+        match name {
+            Some(name) => !username.is_empty() && normalize(username) == name,
+            None => username.is_empty()
+        }
+    };
+    let mut updated: Vec<String> = content.lines()
+        .map(|current| {
+            let trimmed = current.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                return current.to_string();
+            }
+            trimmed.split(';')
+                .map(|part| if is_user(part) { line } else { part.trim() })
+                .collect::<Vec<&str>>()
+                .join(";")
+        })
+        .collect();
+    updated.push(String::new());
+
+    // Written to a temporary file first, so a failure never leaves a truncated users file. The
+    // name is unique to this change and the file gets the original's permissions before any
+    // hash is written to it, so the users file never becomes readable by others
+    let permissions = fs::metadata(path)
+        .map_err(|error| format!("Cannot read {}: {}", path.display(), error))?
+        .permissions();
+    let temporary = PathBuf::from(format!("{}.{}.tmp", path.display(), random_salt(12)));
+    let result = fs::OpenOptions::new().write(true).create_new(true).open(&temporary)
+        .and_then(|mut file| {
+            file.set_permissions(permissions)?;
+            file.write_all(updated.join("\n").as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temporary, path));
+    if result.is_err() {
+        fs::remove_file(&temporary).ok();
+    }
+    result.map_err(|error| format!("Cannot write {}: {}", path.display(), error))
+}
+
 fn parse_port(value: &str) -> Result<u16, String> {
     match value.parse::<u16>() {
         Ok(0) => Err("Port number cannot be zero".to_string()),
@@ -222,13 +1230,269 @@ fn parse_port(value: &str) -> Result<u16, String> {
 }
 
 fn parse_host(value: &str) -> Result<IpAddr, String> {
+    // IPv6 addresses copied from URLs are enclosed in brackets
+    let value = match value.strip_prefix('[') {
+        Some(rest) => rest.strip_suffix(']')
+            .ok_or_else(|| String::from("Missing closing bracket in the IP-address"))?,
+        None => value
+    };
+    if value.contains('%') {
+        return Err(String::from("IPv6 zone identifiers are not supported"));
+    }
     IpAddr::from_str(value).map_err(|_| String::from("Invalid value for an IP-address"))
 }
 
+fn parse_path(value: &str, name: &str) -> Result<String, String> {
+    // Returns the path without a trailing slash, the root is an empty string
+    let value = value.trim().trim_end_matches('/');
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+
+    if !value.starts_with('/') {
+        return Err(format!("{} must start with a slash", name));
+    }
+    for ch in value.chars() {
+        if ch.is_whitespace() || ch.is_control() || ch == '?' || ch == '#' || ch == ';' {
+            return Err(format!("{} cannot contain {:?}", name, ch));
+        }
+    }
+    Ok(value.to_string())
+}
+
+fn parse_base_path(value: &str) -> Result<String, String> {
+    parse_path(value, "Base path")
+}
+
+fn parse_login_path(value: &str) -> Result<String, String> {
+    match parse_path(value, "Login path")? {
+        path if path.is_empty() => Ok("/".to_string()),
+        path if path.starts_with("/proxy:") => Err("Login path cannot be an internal route".to_string()),
+        path => Ok(path)
+    }
+}
+
+fn parse_login_action(value: &str) -> Result<String, String> {
+    match parse_path(value, "Login action")? {
+        path if path.is_empty() => Err("Use --login-path to receive the form at the root".to_string()),
+        path => Ok(path)
+    }
+}
+
+fn parse_cookie_domain(value: &str) -> Result<String, String> {
+    // A leading dot is ignored by browsers, the domain always includes its subdomains
+    let domain = value.trim().trim_start_matches('.').to_lowercase();
+    let valid_label = |label: &str| !label.is_empty() && label.len() <= 63
+        && !label.starts_with('-') && !label.ends_with('-')
+        && label.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '-');
+    if domain.len() <= 253 && domain.split('.').all(valid_label) {
+        Ok(domain)
+    } else {
+        Err(format!("Invalid domain: {}", value.trim()))
+    }
+}
+
+fn parse_realm(value: &str) -> Result<String, String> {
+    // The realm is sent as a quoted string, so it cannot contain quotes or backslashes
+    let value = value.trim();
+    if value.is_empty() {
+        Err("Realm cannot be empty".to_string())
+    } else if value.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        Err("Realm cannot contain quotes, backslashes or control characters".to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+fn parse_allowed_path(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    if !value.starts_with('/') {
+        return Err("Path must start with a slash".to_string());
+    }
+    if value.find('*').is_some_and(|pos| pos != value.len() - 1) {
+        return Err("Only a trailing '*' is supported".to_string());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_route(value: &str) -> Result<(String, Uri), String> {
+    // Format: <path prefix>=<wiki url>
+    let (prefix, url) = value.split_once('=')
+        .ok_or_else(|| "Route must be in the <prefix>=<url> format".to_string())?;
+    let prefix = parse_path(prefix, "Route prefix")?;
+    if prefix.is_empty() {
+        return Err("Use --wiki-url for requests that do not match any route".to_string());
+    }
+    Ok((prefix, parse_wiki_uri(url.trim())?))
+}
+
+fn parse_list<T, F>(value: &str, parse: F) -> Result<Vec<T>, String>
+        where F: Fn(&str) -> Result<T, String> {
+    value.split(',').map(str::trim).map(parse).collect()
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+    // Format: <number>[K|M|G], the suffixes are binary multiples
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1)
+    };
+
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier)) {
+        Some(0) => Err("Size cannot be zero".to_string()),
+        Some(size) => Ok(size),
+        None => Err("Invalid size".to_string())
+    }
+}
+
+fn parse_duration(value: &str) -> Result<u64, String> {
+    // Format: <number>[s|m|h|d], seconds by default
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 60 * 60),
+        Some('d') => (&value[..value.len() - 1], 24 * 60 * 60),
+        _ => (value, 1)
+    };
+
+    match number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier)) {
+        Some(0) => Err("Duration cannot be zero".to_string()),
+        Some(duration) => Ok(duration),
+        None => Err("Invalid duration".to_string())
+    }
+}
+
+fn parse_redirect_status(value: &str) -> Result<StatusCode, String> {
+    match value.trim() {
+        "302" => Ok(StatusCode::FOUND),
+        "303" => Ok(StatusCode::SEE_OTHER),
+        _ => Err("Redirect status must be either 302 or 303".to_string())
+    }
+}
+
+fn parse_retries(value: &str) -> Result<u32, String> {
+    match value.trim().parse::<u32>() {
+        Ok(retries) if retries <= 10 => Ok(retries),
+        Ok(_) => Err("At most 10 retries are allowed".to_string()),
+        Err(_) => Err("Invalid number of retries".to_string())
+    }
+}
+
+fn parse_warm_connections(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(count) if count <= 64 => Ok(count),
+        Ok(_) => Err("At most 64 connections can be warmed".to_string()),
+        Err(_) => Err("Invalid number of connections".to_string())
+    }
+}
+
+fn parse_limit(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(0) => Err("Limit cannot be zero".to_string()),
+        Ok(limit) => Ok(limit),
+        Err(_) => Err("Invalid limit".to_string())
+    }
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    // Format: <count>/<s|m|h>, returned as requests per second
+    let (count, period) = value.trim().split_once('/')
+        .ok_or_else(|| "The rate must be in the <count>/<s|m|h> format".to_string())?;
+    let seconds = match period.trim() {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        _ => return Err("The period must be s, m or h".to_string())
+    };
+    match count.trim().parse::<u32>() {
+        Ok(0) => Err("Rate cannot be zero".to_string()),
+        Ok(count) => Ok(count as f64 / seconds),
+        Err(_) => Err("Invalid number of requests".to_string())
+    }
+}
+
+fn parse_origin(value: &str) -> Result<String, String> {
+    // Format: <scheme>://<host>[:<port>], as sent in the Origin header
+    let value = value.trim().trim_end_matches('/');
+    match value.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() && uri.authority().is_some()
+                && uri.path() == "/" && uri.query().is_none() => Ok(value.to_lowercase()),
+        _ => Err(format!("Invalid origin: {}", value))
+    }
+}
+
+pub fn parse_pepper(value: &str) -> Result<String, String> {
+    let pepper = resolve_env(value)?;
+    if pepper.is_empty() {
+        return Err("Pepper cannot be empty".to_string());
+    }
+    Ok(pepper)
+}
+
+fn parse_upstream_auth(value: &str) -> Result<HeaderValue, String> {
+    // Marked as sensitive so that the credentials never appear in debug output
+    let mut header = HeaderValue::from_str(resolve_env(value)?.trim())
+        .map_err(|_| "Invalid value of the Authorization header".to_string())?;
+    if header.is_empty() {
+        return Err("Authorization header cannot be empty".to_string());
+    }
+    header.set_sensitive(true);
+    Ok(header)
+}
+
+fn parse_header_name(value: &str) -> Result<HeaderName, String> {
+    HeaderName::from_bytes(value.trim().as_bytes())
+        .map_err(|_| format!("Invalid header name: {}", value.trim()))
+}
+
+fn parse_response_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    // Format: <name>: <value>
+    let pos = match value.find(':') {
+        Some(pos) => pos,
+        None => return Err(format!("Header must be in form of \"Name: Value\": {}", value.trim()))
+    };
+    let name = parse_header_name(&value[..pos])?;
+    let header_value = HeaderValue::from_str(value[pos + 1..].trim())
+        .map_err(|_| format!("Invalid value of header {}", name))?;
+    Ok((name, header_value))
+}
+
+fn multiple_values<'a, T, F>(matches: &ArgMatches<'a>, option: &'static str, parse: F) ->
+        Result<Option<Vec<T>>, (&'static str, String)>
+        where F: Fn(&str) -> Result<T, String> {
+    match matches.values_of(option) {
+        Some(values) => values
+            .map(|value| parse(value).map_err(|error| (option, error)))
+            .collect::<Result<Vec<T>, _>>()
+            .map(Some),
+        None => Ok(None)
+    }
+}
+
+fn optional_value<'a, T, F>(matches: &ArgMatches<'a>, option: &'static str, parse: F) ->
+        Result<Option<T>, (&'static str, String)>
+        where F: Fn(&str) -> Result<T, String> {
+    match matches.value_of(option).map(parse) {
+        Some(Ok(value)) => Ok(Some(value)),
+        Some(Err(error)) => Err((option, error)),
+        None => Ok(None)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
-    use super::parse_port;
+    use super::{parse_port, parse_host, parse_login_action, parse_rate, parse_size, parse_base_path, parse_duration, parse_header_name, parse_realm,
+        parse_redirect_status, parse_retries, parse_limit, parse_login_path, parse_origin,
+        parse_response_header, parse_cookie_domain, parse_warm_connections,
+        parse_allowed_path, parse_upstream_auth};
+    use hyper::StatusCode;
+    use super::ProxyConfig;
+    use super::validate_secret;
     use rstest::rstest;
 
     mod test_prasing_username {
@@ -291,10 +1555,14 @@ mod tests {
         }
 
         #[test]
-        fn test_illegal_query(){
+        fn test_query(){
             assert_eq!(
-                parse_wiki_uri("http://localhost/?query"),
-                Err(String::from("URL cannot contain a query"))
+                parse_wiki_uri("http://localhost/?wiki=main"),
+                Ok("http://localhost/?wiki=main".parse().unwrap())
+            );
+            assert_eq!(
+                parse_wiki_uri("http://localhost/path?"),
+                Ok("http://localhost/path".parse().unwrap())
             );
         }
     }
@@ -348,12 +1616,30 @@ mod tests {
     mod test_parsing_credentials {
         use rstest::rstest;
         use hex_literal::hex;
+        use std::time::SystemTime;
         use crate::credentials::{UserCredentials, CredentialsStore};
-        use super::super::parse_credentials;
+        use crate::auth::AuthConfig;
+        use super::super::{parse_credentials, parse_credentials_with_limit, read_users_file};
         use super::super::ProxyConfig;
 
         #[rstest(input, error,
             case ("user:password", "Wrong number of components"),
+            case (
+                "user:ABC:DEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                "Either the username or the salt contains ':'"
+            ),
+            case (
+                "user:ABC:DEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b:editor",
+                "Either the username or the salt contains ':'"
+            ),
+            case (
+                "user:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b:editor:admin",
+                "The list of roles contains ':'"
+            ),
+            case (
+                "user:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408X:editor",
+                "Password hash is not valid (Invalid character at position 64)"
+            ),
             case (
                 "user:s:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
                 "The value for salt is too short"
@@ -366,7 +1652,18 @@ mod tests {
                 ":ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b; \
                 user:FEDCBA:f64671af1dd46e4a00a48a2c7c6a3658d107507391b6eb0d9111b2b3d326512b",
                 "User without a username must be the only user"
-            )
+            ),
+            case (
+                ";:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b;; \
+                user:FEDCBA:f64671af1dd46e4a00a48a2c7c6a3658d107507391b6eb0d9111b2b3d326512b;",
+                "User without a username must be the only user"
+            ),
+            case (
+                "user:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b; user2",
+                "Wrong number of components"
+            ),
+            case ("", "No credentials specified"),
+            case (" ; ;", "No credentials specified"),
         )]
         fn test_invalid_credentials(input: &str, error: &str) {
             assert_eq!(parse_credentials(input).unwrap_err(), error)
@@ -382,6 +1679,24 @@ mod tests {
                     )))
                 ]
             ),
+            case (
+                "user:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b:editor, admin",
+                vec![
+                    (Some("user".to_string()), UserCredentials::new(
+                        "ABCDEF".to_string(),
+                        hex!("291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b"
+                    )).with_roles(vec!["editor".to_string(), "admin".to_string()]))
+                ]
+            ),
+            case (
+                " !user:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                vec![
+                    (Some("user".to_string()), UserCredentials::new(
+                        "ABCDEF".to_string(),
+                        hex!("291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b"
+                    )).with_denied(true))
+                ]
+            ),
             case (
                 ":ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
                 vec![
@@ -410,6 +1725,37 @@ mod tests {
             assert_eq!(parse_credentials(input).unwrap(), expected)
         }
 
+        #[rstest(input, count,
+            case("user1:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b;", 1),
+            case("user1:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b;;", 1),
+            case("user1:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b;; \
+                  user2:FEDCBA:aa3a9608d21b2facdd897c37fc2e34f7c0f569c9bf6cfe4e5e413fb6310d0fc8", 2),
+            case("  user1:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b ;\
+                  \t user2:FEDCBA:aa3a9608d21b2facdd897c37fc2e34f7c0f569c9bf6cfe4e5e413fb6310d0fc8 ; ", 2),
+            case(";:ABCDEF:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b;", 1),
+        )]
+        fn test_empty_parts_skipped(input: &str, count: usize){
+            let users = parse_credentials(input).unwrap();
+            assert_eq!(users.len(), count);
+            assert_eq!(users[0].1, UserCredentials::new(
+                "ABCDEF".to_string(),
+                hex!("291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b")
+            ));
+        }
+
+        #[test]
+        fn test_too_many_users(){
+            let users = (0..3)
+                .map(|index| format!("user{}:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8", index))
+                .collect::<Vec<String>>()
+                .join(";");
+            assert_eq!(parse_credentials_with_limit(&users, 3).unwrap().len(), 3);
+            assert_eq!(
+                parse_credentials_with_limit(&users, 2).unwrap_err(),
+                "Too many users: 3 specified, at most 2 are allowed"
+            );
+        }
+
         #[test]
         fn test_credentials_store(){
             let config = ProxyConfig::from_values(
@@ -423,6 +1769,212 @@ mod tests {
             assert!(config.can_login(Some("user1"), "password"));
             assert!(config.can_login(Some("user2"), "another"));
         }
+
+        #[rstest(case_insensitive, expected,
+            case(false, false),
+            case(true, true)
+        )]
+        fn test_case_insensitive_usernames(case_insensitive: bool, expected: bool){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8; \
+                 User2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
+                 None, None
+            ).unwrap().with_case_insensitive_usernames(case_insensitive);
+            assert!(config.can_login(Some("user1"), "password"));
+            assert_eq!(config.can_login(Some("User1"), "password"), expected);
+            assert_eq!(config.can_login(Some(" user1 "), "password"), expected);
+            assert_eq!(config.can_login(Some("user2"), "another"), expected);
+            assert!(config.can_login(Some("User2"), "another"));
+            assert!(!config.can_login(Some("USER1"), "wrong"));
+        }
+
+        #[test]
+        fn test_denied_users(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8; \
+                 user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
+                 None, None
+            ).unwrap().with_denied_users(vec!["User1".to_string()]);
+            assert!(config.can_login(Some("user1"), "password"));
+            assert!(config.can_login(Some("user2"), "another"));
+
+            let config = config.with_case_insensitive_usernames(true);
+            assert!(!config.can_login(Some("user1"), "password"));
+            assert!(!config.can_login(Some("USER1"), "password"));
+            assert!(config.can_login(Some("user2"), "another"));
+        }
+
+        #[test]
+        fn test_replacing_users(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                 None, None
+            ).unwrap();
+            assert!(config.can_login(Some("user1"), "password"));
+
+            config.set_users(parse_credentials(
+                "user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145"
+            ).unwrap()).unwrap();
+            assert!(!config.can_login(Some("user1"), "password"));
+            assert!(config.can_login(Some("user2"), "another"));
+
+            // A list with duplicates is rejected as a whole and the current users are kept
+            let config = config.with_case_insensitive_usernames(true);
+            assert!(config.set_users(parse_credentials(
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8; \
+                 User1:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145"
+            ).unwrap()).is_err());
+            assert!(config.can_login(Some("user2"), "another"));
+        }
+
+        #[test]
+        fn test_reloading_users_file(){
+            let path = std::env::temp_dir().join(format!("tiddlyproxy-users-{}", std::process::id()));
+            std::fs::write(&path,
+                "# users\n\
+                 user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8\n"
+            ).unwrap();
+
+            let users = read_users_file(&path).unwrap();
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &users, None, None
+            ).unwrap().with_users_file(Some(path.clone()));
+            assert!(config.can_login(Some("user1"), "password"));
+
+            std::fs::write(&path,
+                "user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145\n\n"
+            ).unwrap();
+            assert_eq!(config.reload_users(), Ok(1));
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(!config.can_login(Some("user1"), "password"));
+            assert!(config.can_login(Some("user2"), "another"));
+        }
+
+        #[test]
+        fn test_suspending_user_on_reload(){
+            let path = std::env::temp_dir().join(format!("tiddlyproxy-suspend-{}", std::process::id()));
+            std::fs::write(&path,
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8\n"
+            ).unwrap();
+
+            let users = read_users_file(&path).unwrap();
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &users, None, None
+            ).unwrap().with_users_file(Some(path.clone()));
+            assert!(config.can_login(Some("user1"), "password"));
+
+            std::fs::write(&path,
+                "!user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8\n"
+            ).unwrap();
+            assert_eq!(config.reload_users(), Ok(1));
+            assert!(config.is_denied(Some("user1")));
+            assert!(!config.can_login(Some("user1"), "password"));
+
+            // The suspension is kept when the password is changed
+            config.change_password(Some("user1"), "another").unwrap();
+            let content = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(content.starts_with("!user1:"));
+            assert_eq!(content.lines().count(), 1);
+        }
+
+        #[test]
+        fn test_revoking_tokens(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                 None, None
+            ).unwrap();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+
+            config.revoke_token("expired", now - 10);
+            config.revoke_token("active", now + 100);
+            assert!(!config.is_revoked("expired"));
+            assert!(config.is_revoked("active"));
+            assert!(!config.is_revoked("other"));
+            assert_eq!(config.revoked_tokens.lock().unwrap().len(), 1);
+        }
+
+        #[test]
+        fn test_changing_password(){
+            let path = std::env::temp_dir().join(format!("tiddlyproxy-passwd-{}", std::process::id()));
+            std::fs::write(&path,
+                "# users\n\
+                 user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8:admin\n\
+                 user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145\n"
+            ).unwrap();
+
+            let users = read_users_file(&path).unwrap();
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &users, None, None
+            ).unwrap().with_users_file(Some(path.clone()));
+            assert_eq!(config.change_password(Some("user1"), "changed"), Ok(()));
+            assert_eq!(config.change_password(Some("user3"), "changed"), Err("Unknown user".to_string()));
+            assert!(config.can_login(Some("user1"), "changed"));
+            assert!(!config.can_login(Some("user1"), "password"));
+
+            let content = std::fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = content.lines().collect();
+            assert_eq!(lines[0], "# users");
+            assert!(lines[1].starts_with("user1:") && lines[1].ends_with(":admin"));
+            assert_eq!(lines[2], "user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145");
+
+            assert_eq!(config.reload_users(), Ok(2));
+            std::fs::remove_file(&path).unwrap();
+            assert!(config.can_login(Some("user1"), "changed"));
+            assert_eq!(config.credentials_for(Some("user1")).unwrap().roles(), ["admin"]);
+        }
+
+        #[test]
+        #[cfg(unix)]
+        fn test_changing_password_keeps_permissions(){
+            use std::os::unix::fs::PermissionsExt;
+            let directory = std::env::temp_dir().join(format!("tiddlyproxy-passwd-mode-{}", std::process::id()));
+            std::fs::create_dir(&directory).unwrap();
+            let path = directory.join("users");
+            std::fs::write(&path, "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &read_users_file(&path).unwrap(), None, None
+            ).unwrap().with_users_file(Some(path.clone()));
+            assert_eq!(config.change_password(Some("user1"), "changed"), Ok(()));
+            assert_eq!(config.change_password(Some("user1"), "changed again"), Ok(()));
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            let files = std::fs::read_dir(&directory).unwrap().count();
+            std::fs::remove_dir_all(&directory).unwrap();
+            assert_eq!(mode & 0o777, 0o600);
+            // No temporary file is left behind
+            assert_eq!(files, 1);
+        }
+
+        #[test]
+        fn test_reloading_without_users_file(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                 None, None
+            ).unwrap();
+            assert_eq!(config.reload_users(), Err("Users were not loaded from a file".to_string()));
+        }
     }
 
     #[rstest(value, expected,
@@ -435,4 +1987,579 @@ mod tests {
     fn test_parse_port_number(value: &str, expected: Result<u16, String>){
         assert_eq!(parse_port(value), expected);
     }
+
+    #[rstest(value, expected,
+        case("/proxy:login", Ok("/proxy:login")),
+        case("/auth/", Ok("/auth")),
+        case("auth", Err("Login action must start with a slash")),
+        case("/", Err("Use --login-path to receive the form at the root")),
+    )]
+    fn test_parse_login_action(value: &str, expected: Result<&str, &str>){
+        assert_eq!(parse_login_action(value), expected.map(String::from).map_err(String::from));
+    }
+
+    #[rstest(value, expected,
+        case("127.0.0.1", Ok("127.0.0.1")),
+        case("::1", Ok("::1")),
+        case("[::1]", Ok("::1")),
+        case("[fe80::1]", Ok("fe80::1")),
+        case("[::1", Err("Missing closing bracket in the IP-address")),
+        case("[127.0.0.x]", Err("Invalid value for an IP-address")),
+        case("[]", Err("Invalid value for an IP-address")),
+        case("fe80::1%eth0", Err("IPv6 zone identifiers are not supported")),
+        case("[fe80::1%25eth0]", Err("IPv6 zone identifiers are not supported")),
+        case("localhost", Err("Invalid value for an IP-address")),
+    )]
+    fn test_parse_host(value: &str, expected: Result<&str, &str>){
+        assert_eq!(
+            parse_host(value),
+            expected.map(|ip| ip.parse().unwrap()).map_err(String::from)
+        );
+    }
+
+    #[rstest(value, expected,
+        case("1024", Ok(1024)),
+        case("16K", Ok(16 * 1024)),
+        case("5m", Ok(5 * 1024 * 1024)),
+        case("2G", Ok(2 * 1024 * 1024 * 1024)),
+        case("0", Err("Size cannot be zero".to_string())),
+        case("12X", Err("Invalid size".to_string())),
+        case("M", Err("Invalid size".to_string()))
+    )]
+    fn test_parse_size(value: &str, expected: Result<u64, String>){
+        assert_eq!(parse_size(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("/", Ok("".to_string())),
+        case("/wiki", Ok("/wiki".to_string())),
+        case("/wiki/", Ok("/wiki".to_string())),
+        case("/a/b", Ok("/a/b".to_string())),
+        case("wiki", Err("Base path must start with a slash".to_string())),
+        case("/wi ki", Err("Base path cannot contain ' '".to_string())),
+        case("/wiki?a", Err("Base path cannot contain '?'".to_string()))
+    )]
+    fn test_parse_base_path(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_base_path(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("/", Ok("/".to_string())),
+        case("/login/", Ok("/login".to_string())),
+        case("login", Err("Login path must start with a slash".to_string())),
+        case("/proxy:login", Err("Login path cannot be an internal route".to_string()))
+    )]
+    fn test_parse_login_path(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_login_path(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("90", Ok(90)),
+        case("30s", Ok(30)),
+        case("15m", Ok(15 * 60)),
+        case("2h", Ok(2 * 60 * 60)),
+        case("1d", Ok(24 * 60 * 60)),
+        case("0m", Err("Duration cannot be zero".to_string())),
+        case("1w", Err("Invalid duration".to_string())),
+        case("-5", Err("Invalid duration".to_string()))
+    )]
+    fn test_parse_duration(value: &str, expected: Result<u64, String>){
+        assert_eq!(parse_duration(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("302", Ok(StatusCode::FOUND)),
+        case("303", Ok(StatusCode::SEE_OTHER)),
+        case("301", Err("Redirect status must be either 302 or 303".to_string()))
+    )]
+    fn test_parse_redirect_status(value: &str, expected: Result<StatusCode, String>){
+        assert_eq!(parse_redirect_status(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("0", Ok(0)),
+        case("3", Ok(3)),
+        case("11", Err("At most 10 retries are allowed".to_string())),
+        case("-1", Err("Invalid number of retries".to_string()))
+    )]
+    fn test_parse_retries(value: &str, expected: Result<u32, String>){
+        assert_eq!(parse_retries(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("0", Ok(0)),
+        case("8", Ok(8)),
+        case("65", Err("At most 64 connections can be warmed".to_string())),
+        case("many", Err("Invalid number of connections".to_string()))
+    )]
+    fn test_parse_warm_connections(value: &str, expected: Result<usize, String>){
+        assert_eq!(parse_warm_connections(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("1", Ok(1)),
+        case(" 64", Ok(64)),
+        case("0", Err("Limit cannot be zero".to_string())),
+        case("many", Err("Invalid limit".to_string()))
+    )]
+    fn test_parse_limit(value: &str, expected: Result<usize, String>){
+        assert_eq!(parse_limit(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("10/s", Ok(10.0)),
+        case(" 300 / m ", Ok(5.0)),
+        case("1800/h", Ok(0.5)),
+        case("10", Err("The rate must be in the <count>/<s|m|h> format".to_string())),
+        case("10/d", Err("The period must be s, m or h".to_string())),
+        case("0/s", Err("Rate cannot be zero".to_string())),
+        case("-1/s", Err("Invalid number of requests".to_string())),
+    )]
+    fn test_parse_rate(value: &str, expected: Result<f64, String>){
+        assert_eq!(parse_rate(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case(" Team wiki ", Ok("Team wiki".to_string())),
+        case("", Err("Realm cannot be empty".to_string())),
+        case("Say \"hi\"", Err("Realm cannot contain quotes, backslashes or control characters".to_string())),
+        case("a\\b", Err("Realm cannot contain quotes, backslashes or control characters".to_string())),
+    )]
+    fn test_parse_realm(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_realm(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case(" /recipes/default/tiddlers/*", Ok("/recipes/default/tiddlers/*".to_string())),
+        case("/status", Ok("/status".to_string())),
+        case("status", Err("Path must start with a slash".to_string())),
+        case("/recipes/*/tiddlers", Err("Only a trailing '*' is supported".to_string()))
+    )]
+    fn test_parse_allowed_path(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_allowed_path(value), expected);
+    }
+
+    #[rstest(path, expected,
+        case("/", true),
+        case("/recipes/default/tiddlers.json", true),
+        case("/recipes/default/tiddlers/New%20Tiddler", true),
+        case("/recipes/default/tiddlers", false),
+        case("/status", false),
+        case("/admin", false),
+        case("/public/page", true),
+        case("/public/../private", false),
+        case("/public/%2e%2e/private", false),
+        case("/public/%2E%2E%2Fprivate", false),
+        case("/public/..%5Cprivate", false),
+        case("/public/./page", false),
+        case("/public/Chapter..2", true),
+        case("/%70ublic/page", true),
+    )]
+    fn test_allowed_paths(path: &str, expected: bool){
+        let config = ProxyConfig::from_values(
+            "localhost",
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap().with_allowed_paths(Some(vec![
+            "/".to_string(),
+            "/recipes/default/tiddlers.json".to_string(),
+            "/recipes/default/tiddlers/*".to_string(),
+            "/public/*".to_string()
+        ]));
+        assert_eq!(config.is_path_allowed(path), expected);
+    }
+
+    #[rstest(value, expected,
+        case("example.com", Ok("example.com".to_string())),
+        case(" .Example.COM", Ok("example.com".to_string())),
+        case("wiki-1.example.com", Ok("wiki-1.example.com".to_string())),
+        case("localhost", Ok("localhost".to_string())),
+        case("", Err("Invalid domain: ".to_string())),
+        case("example..com", Err("Invalid domain: example..com".to_string())),
+        case("-wiki.example.com", Err("Invalid domain: -wiki.example.com".to_string())),
+        case("example.com:8080", Err("Invalid domain: example.com:8080".to_string())),
+        case("wiki example.com", Err("Invalid domain: wiki example.com".to_string()))
+    )]
+    fn test_parse_cookie_domain(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_cookie_domain(value), expected);
+    }
+
+    #[rstest(value, expected,
+        case("https://example.com", Ok("https://example.com".to_string())),
+        case("http://Localhost:8080/", Ok("http://localhost:8080".to_string())),
+        case("example.com", Err("Invalid origin: example.com".to_string())),
+        case("https://example.com/wiki", Err("Invalid origin: https://example.com/wiki".to_string()))
+    )]
+    fn test_parse_origin(value: &str, expected: Result<String, String>){
+        assert_eq!(parse_origin(value), expected);
+    }
+
+    #[test]
+    fn test_parse_upstream_auth(){
+        std::env::set_var("TIDDLYPROXY_TEST_UPSTREAM_AUTH", "Bearer token");
+        let header = parse_upstream_auth("env:TIDDLYPROXY_TEST_UPSTREAM_AUTH").unwrap();
+        assert_eq!(header, "Bearer token");
+        assert!(header.is_sensitive());
+        assert_eq!(parse_upstream_auth(" Basic dXNlcjpwYXNz ").unwrap(), "Basic dXNlcjpwYXNz");
+        assert_eq!(parse_upstream_auth(" "), Err("Authorization header cannot be empty".to_string()));
+        assert_eq!(parse_upstream_auth("a\u{7f}"), Err("Invalid value of the Authorization header".to_string()));
+    }
+
+    #[test]
+    fn test_parse_header_name(){
+        assert_eq!(parse_header_name(" X-Powered-By").unwrap().as_str(), "x-powered-by");
+        assert_eq!(parse_header_name("Bad Header"), Err("Invalid header name: Bad Header".to_string()));
+    }
+
+    #[rstest(value, expected,
+        case("X-Frame-Options: DENY", Ok(("x-frame-options", "DENY"))),
+        case("Content-Security-Policy:default-src 'self'; img-src *",
+             Ok(("content-security-policy", "default-src 'self'; img-src *"))),
+        case("X-Frame-Options", Err("Header must be in form of \"Name: Value\": X-Frame-Options".to_string())),
+        case("Bad Header: 1", Err("Invalid header name: Bad Header".to_string())),
+        case("X-Test: a\u{7f}b", Err("Invalid value of header x-test".to_string())),
+    )]
+    fn test_parse_response_header(value: &str, expected: Result<(&str, &str), String>){
+        assert_eq!(
+            parse_response_header(value).map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string())),
+            expected.map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+    }
+
+    #[rstest(host, port, expected,
+        case(None, None, vec!["127.0.0.1:3000"]),
+        case(Some("::1"), Some("8080"), vec!["[::1]:8080"]),
+        case(Some("127.0.0.1, ::1"), None, vec!["127.0.0.1:3000", "[::1]:3000"]),
+        case(Some("127.0.0.1"), Some("8080,8081"), vec!["127.0.0.1:8080", "127.0.0.1:8081"]),
+        case(Some("127.0.0.1,127.0.0.1"), Some("8080"), vec!["127.0.0.1:8080"]),
+    )]
+    fn test_socket_addrs(host: Option<&str>, port: Option<&str>, expected: Vec<&str>){
+        let config = ProxyConfig::from_values(
+            "localhost",
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            host, port
+        ).unwrap();
+        let expected: Vec<std::net::SocketAddr> = expected.iter().map(|addr| addr.parse().unwrap()).collect();
+        assert_eq!(config.socket_addrs(), &expected[..]);
+    }
+
+    #[test]
+    fn test_invalid_host_in_list(){
+        let error = ProxyConfig::from_values(
+            "localhost",
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            Some("127.0.0.1,localhost"), None
+        ).unwrap_err();
+        assert_eq!(error, ("host", "Invalid value for an IP-address".to_string()));
+    }
+
+    #[rstest(secret, expected,
+        case(
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "Secret cannot consist of a single repeated byte"
+        ),
+        case(
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "Secret cannot consist of a single repeated byte"
+        ),
+        case(
+            "0102030401020304010203040102030401020304010203040102030401020304",
+            "Secret is not random enough, use gensecret"
+        ),
+    )]
+    fn test_weak_secret(secret: &str, expected: &str){
+        let error = ProxyConfig::from_values(
+            "localhost", secret,
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap_err();
+        assert_eq!(error, ("secret", expected.to_string()));
+    }
+
+    #[test]
+    fn test_random_secret(){
+        let config = ProxyConfig::from_values(
+            "localhost",
+            "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        );
+        assert!(config.is_ok());
+        assert_eq!(validate_secret(b"01234567890123456789012345678901"), Ok(()));
+    }
+
+    mod test_from_args {
+        use rstest::rstest;
+        use clap::{App, load_yaml};
+        use crate::credentials::CredentialsStore;
+        use super::super::{ProxyConfig, parse_route};
+
+        fn from_args(args: &[&str]) -> Result<ProxyConfig, (&'static str, String)> {
+            let yaml = load_yaml!("../data/arguments.yml");
+            let matches = App::from_yaml(yaml).get_matches_from(args);
+            ProxyConfig::from_args(matches.subcommand_matches("run").unwrap())
+        }
+
+        #[rstest(flag, case("--wiki-url"), case("--wiki_url"))]
+        fn test_wiki_url_flag(flag: &str){
+            // The underscore spelling is kept as an alias for existing scripts
+            let config = from_args(&[
+                "tiddlyproxy", "run", flag, "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ]).unwrap();
+            assert_eq!(config.remote_uri().to_string(), "http://localhost:8080/");
+        }
+
+        #[rstest(args, nodelay, keepalive,
+            case(&[], false, None),
+            case(&["--tcp-nodelay"], true, None),
+            case(&["--tcp-keepalive", "2m"], false, Some(120)),
+        )]
+        fn test_tcp_options(args: &[&str], nodelay: bool, keepalive: Option<u64>){
+            let mut all_args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ];
+            all_args.extend_from_slice(args);
+            let config = from_args(&all_args).unwrap();
+            assert_eq!(config.tcp_nodelay(), nodelay);
+            assert_eq!(config.tcp_keepalive(), keepalive);
+        }
+
+        #[rstest(args, expected,
+            case(&["--http-redirect-port", "8081"], Ok(vec!["127.0.0.1:8081"])),
+            case(&["--host", "127.0.0.1,::1", "--http-redirect-port", "8081"], Ok(vec!["127.0.0.1:8081", "[::1]:8081"])),
+            case(&[], Ok(vec![])),
+            case(&["--port", "8081", "--http-redirect-port", "8081"], Err("http-redirect-port")),
+        )]
+        fn test_http_redirect_port(args: &[&str], expected: Result<Vec<&str>, &str>){
+            let mut all_args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ];
+            all_args.extend_from_slice(args);
+            match (from_args(&all_args), expected) {
+                (Ok(config), Ok(addrs)) => assert_eq!(
+                    config.http_redirect_addrs(),
+                    addrs.iter().map(|addr| addr.parse().unwrap()).collect::<Vec<std::net::SocketAddr>>()
+                ),
+                (Err((field, _)), Err(expected)) => assert_eq!(field, expected),
+                (result, _) => panic!("Unexpected result: {:?}", result.map(|_| ()))
+            }
+        }
+
+        #[rstest(args, expected,
+            case(&["--login-action", "/auth"], Ok("/auth")),
+            case(&["--login-action", "/logout"], Err("login-action")),
+            case(&["--login-action", "/logout/"], Err("login-action")),
+            case(&["--login-path", "/login", "--login-action", "/logout"], Ok("/logout")),
+            case(&["--login-path", "/login/", "--login-action", "/login/logout"], Err("login-action")),
+        )]
+        fn test_login_action(args: &[&str], expected: Result<&str, &str>){
+            let mut all_args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ];
+            all_args.extend_from_slice(args);
+            match (from_args(&all_args), expected) {
+                (Ok(config), Ok(action)) => assert_eq!(config.login_action(), Some(action)),
+                (Err((field, _)), Err(expected)) => assert_eq!(field, expected),
+                (result, _) => panic!("Unexpected result: {:?}", result.map(|_| ()))
+            }
+        }
+
+        #[rstest(args, expected,
+            case(&["--http-redirect-port", "8000"], Ok(443)),
+            case(&["--http-redirect-port", "8000", "--https-port", "8443"], Ok(8443)),
+            case(&["--http-redirect-port", "8000", "--https-port", "port"], Err("https-port")),
+        )]
+        fn test_https_port(args: &[&str], expected: Result<u16, &str>){
+            let mut all_args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ];
+            all_args.extend_from_slice(args);
+            match (from_args(&all_args), expected) {
+                (Ok(config), Ok(port)) => assert_eq!(config.https_port(), port),
+                (Err((field, _)), Err(expected)) => assert_eq!(field, expected),
+                (result, _) => panic!("Unexpected result: {:?}", result.map(|_| ()))
+            }
+        }
+
+        #[rstest(users, case_insensitive, valid,
+            case("user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8;\
+                  User:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145", false, true),
+            case("user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8;\
+                  User:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145", true, false),
+            case("user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8;\
+                  user:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145", false, false),
+        )]
+        fn test_duplicate_usernames(users: &str, case_insensitive: bool, valid: bool){
+            let mut args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", users
+            ];
+            if case_insensitive {
+                args.push("--case-insensitive-usernames");
+            }
+            match from_args(&args) {
+                Ok(config) => assert!(valid && config.user_count() == 2),
+                Err((field, _)) => assert!(!valid && field == "users")
+            }
+        }
+
+        #[rstest(max_users, valid,
+            case("2", true),
+            case("1", false),
+        )]
+        fn test_max_users(max_users: &str, valid: bool){
+            let result = from_args(&[
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8;\
+                            user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
+                "--max-users", max_users
+            ]);
+            match result {
+                Ok(config) => assert!(valid && config.user_count() == 2),
+                Err((field, error)) => {
+                    assert!(!valid);
+                    assert_eq!(field, "users");
+                    assert_eq!(error, "Too many users: 2 specified, at most 1 are allowed");
+                }
+            }
+        }
+
+        #[test]
+        fn test_secret_from_env(){
+            std::env::set_var(
+                "TIDDLYPROXY_TEST_SECRET",
+                "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC"
+            );
+            std::env::set_var(
+                "TIDDLYPROXY_TEST_USERS",
+                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            );
+            let config = from_args(&[
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", "env:TIDDLYPROXY_TEST_SECRET",
+                "--users", "env:TIDDLYPROXY_TEST_USERS"
+            ]).unwrap();
+            assert!(config.can_login(Some("user"), "password"));
+        }
+
+        #[rstest(content, mode, strict, expected,
+            case("59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC\n", 0o600, true, Ok(())),
+            case("59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC", 0o644, false, Ok(())),
+            case("59C2664A8467D3AE144141C27669EEE7", 0o600, false,
+                 Err(("secret", "String is too short, 64 hex digits expected".to_string()))),
+            case("59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC", 0o640, true,
+                 Err(("secret-file", "is readable by other users, restrict it with chmod 600".to_string()))),
+        )]
+        #[cfg(unix)]
+        fn test_secret_file(content: &str, mode: u32, strict: bool, expected: Result<(), (&str, String)>){
+            use std::os::unix::fs::PermissionsExt;
+            let path = std::env::temp_dir().join(format!("tiddlyproxy-secret-{}-{:o}-{}", std::process::id(), mode, strict));
+            std::fs::write(&path, content).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+
+            let mut args = vec![
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret-file", path.to_str().unwrap(),
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ];
+            if strict {
+                args.push("--strict-secret-file");
+            }
+            let result = from_args(&args);
+            std::fs::remove_file(&path).unwrap();
+            match (result, expected) {
+                (Ok(config), Ok(())) => assert!(config.can_login(Some("user"), "password")),
+                (Err((option, error)), Err((expected_option, expected_error))) => {
+                    assert_eq!(option, expected_option);
+                    assert!(error.ends_with(&expected_error), "{}", error);
+                },
+                (result, _) => panic!("Unexpected result: {:?}", result.map(|_| ()))
+            }
+        }
+
+        #[rstest(args, path, expected,
+            case(&["--route", "/team=localhost:8081"], "/team/x", Some(("/team", "http://localhost:8081/"))),
+            case(&["--route", "/team=localhost:8081"], "/other", None),
+            case(&["--route", "/team=localhost:8081", "--wiki-url", "localhost:8080"], "/other",
+                 Some(("", "http://localhost:8080/"))),
+            case(&["--route", "/a=localhost:8081", "--route", "/a/b/=localhost:8082"], "/a/b/c",
+                 Some(("/a/b", "http://localhost:8082/"))),
+            case(&["--route", "/a=localhost:8081", "--route", "/a/b=localhost:8082"], "/a/bc",
+                 Some(("/a", "http://localhost:8081/"))),
+            case(&["--wiki-url", "localhost:8080"], "/other", Some(("", "http://localhost:8080/"))),
+        )]
+        fn test_routes(args: &[&str], path: &str, expected: Option<(&str, &str)>){
+            let args: Vec<&str> = ["tiddlyproxy", "run", "--secret",
+                "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ].iter().chain(args.iter()).copied().collect();
+            let config = from_args(&args).unwrap();
+            let upstream = config.upstream_for(path).map(|(prefix, uri)| (prefix.to_string(), uri.to_string()));
+            assert_eq!(upstream, expected.map(|(prefix, uri)| (prefix.to_string(), uri.to_string())));
+        }
+
+        #[test]
+        fn test_upstream_availability(){
+            let args = ["tiddlyproxy", "run", "--secret",
+                "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "--wiki-url", "localhost:8080", "--route", "/a=localhost:8081", "--route", "/b=localhost:8080",
+                "--offline-banner", "Offline"];
+            let config = from_args(&args).unwrap();
+            let upstreams: Vec<String> = config.upstreams().iter().map(|uri| uri.to_string()).collect();
+            assert_eq!(upstreams, vec!["http://localhost:8080/", "http://localhost:8081/"]);
+
+            let routed = config.upstream_for("/a").unwrap().1;
+            config.set_upstream_available(routed, false);
+            assert_eq!(config.offline_banner(routed), Some("Offline"));
+            assert_eq!(config.offline_banner(config.remote_uri()), None);
+            config.set_upstream_available(routed, true);
+            assert_eq!(config.offline_banner(routed), None);
+        }
+
+        #[rstest(value, expected,
+            case("/team=localhost:8081", Ok(("/team", "http://localhost:8081/"))),
+            case(" /team/ = http://localhost:8081/wiki", Ok(("/team", "http://localhost:8081/wiki"))),
+            case("/team", Err("Route must be in the <prefix>=<url> format")),
+            case("team=localhost:8081", Err("Route prefix must start with a slash")),
+            case("/=localhost:8081", Err("Use --wiki-url for requests that do not match any route")),
+            case("/team=https://localhost", Err("Protocol not supported: https")),
+        )]
+        fn test_parse_route(value: &str, expected: Result<(&str, &str), &str>){
+            let route = parse_route(value).map(|(prefix, uri)| (prefix, uri.to_string()));
+            assert_eq!(route, expected
+                .map(|(prefix, uri)| (prefix.to_string(), uri.to_string()))
+                .map_err(String::from));
+        }
+
+        #[rstest(secret, users, expected,
+            case("env:TIDDLYPROXY_TEST_MISSING", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8", "secret"),
+            case("59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC", "env:TIDDLYPROXY_TEST_MISSING", "users"),
+        )]
+        fn test_missing_env(secret: &str, users: &str, expected: &str){
+            let error = from_args(&[
+                "tiddlyproxy", "run", "--wiki-url", "localhost:8080",
+                "--secret", secret, "--users", users
+            ]).unwrap_err();
+            assert_eq!(error, (
+                expected,
+                "Environment variable TIDDLYPROXY_TEST_MISSING is not set".to_string()
+            ));
+        }
+    }
 }
@@ -0,0 +1,106 @@
+use hyper::{Request, Response, Body, StatusCode, Method};
+use hyper::header::HeaderValue;
+
+
+const ALLOWED_METHODS: &str = "GET, HEAD, PUT, POST, DELETE, OPTIONS";
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+pub fn allowed_origin<B>(request: &Request<B>, origins: &[String]) -> Option<HeaderValue> {
+    let origin = request.headers().get("Origin")?;
+    let value = origin.to_str().ok()?.trim_end_matches('/');
+    if origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(value)) {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+pub fn is_preflight<B>(request: &Request<B>) -> bool {
+    request.method() == Method::OPTIONS &&
+        request.headers().contains_key("Access-Control-Request-Method")
+}
+
+pub fn preflight_response<B>(request: &Request<B>, origin: HeaderValue) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+        .header("Access-Control-Max-Age", PREFLIGHT_MAX_AGE);
+    if let Some(headers) = request.headers().get("Access-Control-Request-Headers") {
+        response = response.header("Access-Control-Allow-Headers", headers);
+    }
+    let mut response = response.body(Body::empty()).unwrap();
+    add_headers(&mut response, origin);
+    response
+}
+
+pub fn add_headers(response: &mut Response<Body>, origin: HeaderValue) {
+    // The session cookie is sent along, so the origin is echoed instead of using "*"
+    let headers = response.headers_mut();
+    headers.insert("Access-Control-Allow-Origin", origin);
+    headers.insert("Access-Control-Allow-Credentials", HeaderValue::from_static("true"));
+    headers.append("Vary", HeaderValue::from_static("Origin"));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use hyper::{Request, Response, Body};
+    use super::{allowed_origin, is_preflight, preflight_response, add_headers};
+
+    fn origins() -> Vec<String> {
+        vec!["https://example.com".to_string(), "http://localhost:8080".to_string()]
+    }
+
+    #[rstest(origin, expected,
+        case(Some("https://example.com"), true),
+        case(Some("https://EXAMPLE.com/"), true),
+        case(Some("http://localhost:8080"), true),
+        case(Some("https://example.org"), false),
+        case(Some("http://example.com"), false),
+        case(None, false),
+    )]
+    fn test_allowed_origin(origin: Option<&str>, expected: bool) {
+        let mut request = Request::builder();
+        if let Some(origin) = origin {
+            request = request.header("Origin", origin);
+        }
+        let request = request.body(()).unwrap();
+        assert_eq!(allowed_origin(&request, &origins()).is_some(), expected);
+    }
+
+    #[test]
+    fn test_preflight() {
+        let request = Request::builder()
+            .method("OPTIONS")
+            .header("Origin", "https://example.com")
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "content-type, x-requested-with")
+            .body(()).unwrap();
+        assert!(is_preflight(&request));
+
+        let origin = allowed_origin(&request, &origins()).unwrap();
+        let response = preflight_response(&request, origin);
+        let headers = response.headers();
+        assert_eq!(response.status(), 204);
+        assert_eq!(headers.get("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+        assert_eq!(headers.get("Access-Control-Allow-Credentials").unwrap(), "true");
+        assert_eq!(headers.get("Access-Control-Allow-Methods").unwrap(), "GET, HEAD, PUT, POST, DELETE, OPTIONS");
+        assert_eq!(headers.get("Access-Control-Allow-Headers").unwrap(), "content-type, x-requested-with");
+        assert_eq!(headers.get("Vary").unwrap(), "Origin");
+
+        let request = Request::builder().method("OPTIONS").body(()).unwrap();
+        assert!(!is_preflight(&request));
+    }
+
+    #[test]
+    fn test_adding_headers() {
+        let mut response = Response::builder()
+            .header("Vary", "Accept-Encoding")
+            .body(Body::empty()).unwrap();
+        add_headers(&mut response, "https://example.com".parse().unwrap());
+        let headers = response.headers();
+        assert_eq!(headers.get("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+        assert_eq!(headers.get_all("Vary").iter().collect::<Vec<_>>(), vec!["Accept-Encoding", "Origin"]);
+    }
+}
@@ -1,41 +1,127 @@
 use sha2::{Sha256, Digest};
 use generic_array::{GenericArray};
 use generic_array::typenum::U32;
+use rand::prelude::*;
+use rand::distributions::{Alphanumeric};
+use rand_chacha::ChaCha20Rng;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct UserCredentials{
     salt: String,
-    password_hash: [u8;32]
+    password_hash: [u8;32],
+    roles: Vec<String>,
+    denied: bool
 }
 
 impl UserCredentials {
     pub fn new(salt: String, hash: [u8;32]) -> UserCredentials{
         UserCredentials {
             salt: salt,
-            password_hash: hash
+            password_hash: hash,
+            roles: Vec::new(),
+            denied: false
         }
     }
+
+    pub fn with_roles(mut self, roles: Vec<String>) -> UserCredentials {
+        self.roles = roles;
+        self
+    }
+
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    pub fn with_denied(mut self, denied: bool) -> UserCredentials {
+        self.denied = denied;
+        self
+    }
+
+    pub fn is_denied(&self) -> bool {
+        self.denied
+    }
+
+    pub fn with_password(&self, password: &str, pepper: Option<&str>) -> UserCredentials {
+        // A fresh salt is generated on every change, roles and suspension are kept
+        let salt = random_salt(DEFAULT_SALT_LENGTH);
+        let password_hash = generate_hash(&salt, password, pepper).into();
+        UserCredentials{ salt, password_hash, roles: self.roles.clone(), denied: self.denied }
+    }
+
+    pub fn to_credentials_string(&self, username: Option<&str>) -> String {
+        // Same format as accepted by --user and the users file
+        let prefix = if self.denied { "!" } else { "" };
+        let mut result = format!("{}{}:{}:", prefix, username.unwrap_or(""), self.salt);
+        for byte in self.password_hash.iter() {
+            result.push_str(&format!("{:02X}", byte));
+        }
+        if !self.roles.is_empty() {
+            result.push(':');
+            result.push_str(&self.roles.join(","));
+        }
+        result
+    }
+}
+
+pub const DEFAULT_SALT_LENGTH: usize = 7;
+
+pub fn random_salt(length: usize) -> String {
+    let rng = ChaCha20Rng::from_entropy();
+    rng.sample_iter(Alphanumeric).take(length).collect()
 }
 
-pub fn generate_hash(salt: &str, password: &str) -> GenericArray<u8, U32>{
+// Hashed in place of a missing user's salt, the result is never accepted
+const DUMMY_SALT: &str = "tiddlyproxy:no-such-user";
+
+pub fn generate_hash(salt: &str, password: &str, pepper: Option<&str>) -> GenericArray<u8, U32>{
+    // Hashes without a pepper stay unchanged. With one, every field is prefixed with its length,
+    // otherwise a colon could be moved between the password and the pepper without changing the hash
     let mut hasher = Sha256::new();
-    hasher.update(salt);
-    hasher.update(b":");
-    hasher.update(password);
+    match pepper {
+        None => {
+            hasher.update(salt);
+            hasher.update(b":");
+            hasher.update(password);
+        },
+        Some(pepper) => {
+            for field in &[salt, password, pepper] {
+                hasher.update((field.len() as u64).to_be_bytes());
+                hasher.update(field);
+            }
+        }
+    }
     hasher.finalize()
 }
 
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn expected_hash(credentials: Option<&UserCredentials>) -> (&str, [u8; 32]) {
+    match credentials {
+        Some(credentials) => (&credentials.salt, credentials.password_hash),
+        None => (DUMMY_SALT, [0; 32])
+    }
+}
+
 pub trait CredentialsStore{
-    fn credentials_for<'a>(&'a self, name: Option<&str>) -> Option<&'a UserCredentials>;
+    fn credentials_for(&self, name: Option<&str>) -> Option<UserCredentials>;
 
-    fn can_login(&self, name: Option<&str>, password: &str) -> bool{
-        let credentials = match self.credentials_for(name) {
-            Some(credentials) => credentials,
-            None => return false
-        };
+    fn pepper(&self) -> Option<&str> {
+        None
+    }
 
-        let hash = generate_hash(&credentials.salt, password);
-        credentials.password_hash[..] == hash[..]
+    fn is_denied(&self, _name: Option<&str>) -> bool {
+        false
+    }
+
+    fn can_login(&self, name: Option<&str>, password: &str) -> bool{
+        // The password is hashed even if the user does not exist, so that the
+        // response time doesn't reveal which usernames are valid
+        let credentials = self.credentials_for(name);
+        let (salt, password_hash) = expected_hash(credentials.as_ref());
+        let hash = generate_hash(salt, password, self.pepper());
+        constant_time_eq(&password_hash, &hash) && credentials.is_some() && !self.is_denied(name)
     }
 
     fn requires_username(&self) -> bool {
@@ -47,12 +133,12 @@ pub trait CredentialsStore{
 #[cfg(test)]
 mod tests {
     use hex_literal::hex;
-    use super::{CredentialsStore, UserCredentials};
+    use super::{CredentialsStore, UserCredentials, constant_time_eq, expected_hash, generate_hash, DUMMY_SALT};
 
     struct NoUserStore;
 
     impl CredentialsStore for NoUserStore {
-        fn credentials_for<'a>(&'a self, _name: Option<&str>) -> Option<&'a UserCredentials> {
+        fn credentials_for(&self, _name: Option<&str>) -> Option<UserCredentials> {
             None
         }
     }
@@ -61,6 +147,38 @@ mod tests {
     fn test_unknown_user() {
         let store = NoUserStore{};
         assert!(! store.can_login(Some("user"), "password"));
+        assert!(! store.can_login(None, ""));
+    }
+
+    #[test]
+    fn test_unknown_user_is_hashed() {
+        let credentials = UserCredentials::new("salt".to_string(), [1; 32]);
+        assert_eq!(expected_hash(Some(&credentials)), ("salt", [1; 32]));
+        assert_eq!(expected_hash(None), (DUMMY_SALT, [0; 32]));
+    }
+
+    #[test]
+    fn test_peppered_fields_are_separated() {
+        assert_eq!(
+            generate_hash("salt", "password", None).as_slice(),
+            hex!("291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b")
+        );
+        assert_ne!(generate_hash("salt", "password", Some("pepper")), generate_hash("salt", "password", None));
+        assert_ne!(
+            generate_hash("salt", "pass:word", Some("pepper")),
+            generate_hash("salt", "pass", Some("word:pepper"))
+        );
+        assert_ne!(
+            generate_hash("salt", "password:", Some("pepper")),
+            generate_hash("salt", "password", Some(":pepper"))
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
     }
 
     struct AllUsersStore{
@@ -68,8 +186,8 @@ mod tests {
     }
 
     impl CredentialsStore for AllUsersStore {
-        fn credentials_for<'a>(&'a self, _name: Option<&str>) -> Option<&'a UserCredentials> {
-            Some(&self.credential)
+        fn credentials_for(&self, _name: Option<&str>) -> Option<UserCredentials> {
+            Some(self.credential.clone())
         }
     }
 
@@ -95,4 +213,32 @@ mod tests {
         assert!(store.can_login(Some("user"), "password"));
     }
 
+    struct DeniedUserStore{
+        store: AllUsersStore
+    }
+
+    impl CredentialsStore for DeniedUserStore {
+        fn credentials_for(&self, name: Option<&str>) -> Option<UserCredentials> {
+            self.store.credentials_for(name)
+        }
+
+        fn is_denied(&self, name: Option<&str>) -> bool {
+            name == Some("denied")
+        }
+    }
+
+    #[test]
+    fn test_denied() {
+        let store = DeniedUserStore{
+            store: AllUsersStore{
+                credential:UserCredentials::new(
+                    "salt".to_string(),
+                    hex!("291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b")
+                )
+            }
+        };
+        assert!(! store.can_login(Some("denied"), "password"));
+        assert!(store.can_login(Some("user"), "password"));
+    }
+
 }
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// Once this many clients are known, the half that was updated longest ago is forgotten
+const MAX_CLIENTS: usize = 1024;
+// Failed logins are forgotten when no other attempt fails for this long
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+
+#[derive(Debug, Default)]
+pub struct UserLimiter {
+    limit: Option<usize>,
+    active: Arc<Mutex<HashMap<String, usize>>>
+}
+
+pub struct UserPermit {
+    key: String,
+    active: Arc<Mutex<HashMap<String, usize>>>
+}
+
+impl UserLimiter {
+    pub fn new(limit: Option<usize>) -> UserLimiter {
+        UserLimiter{ limit, active: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn acquire(&self, key: &str) -> Option<UserPermit> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.get(key).copied().unwrap_or(0);
+        if self.limit.is_some_and(|limit| count >= limit) {
+            return None;
+        }
+        active.insert(key.to_string(), count + 1);
+        Some(UserPermit{ key: key.to_string(), active: self.active.clone() })
+    }
+}
+
+impl Drop for UserPermit {
+    fn drop(&mut self) {
+        // Entries are removed once the last request finishes, so the map only holds active users
+        let mut active = self.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    updated: Instant
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    // Either part is only created once the client is limited by it
+    bucket: Option<Bucket>,
+    failures: Option<Failures>
+}
+
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    // Requests per second and the number of requests that may be made at once
+    rate: Option<(f64, f64)>,
+    // The longest delay applied to a failed login, failures are not delayed when unset
+    max_login_delay: Option<Duration>,
+    // Request rates and failed logins are kept together, so a client is forgotten as a whole
+    clients: Mutex<HashMap<String, Client>>
+}
+
+impl RateLimiter {
+    pub fn with_rate(mut self, rate: Option<(f64, u32)>) -> RateLimiter {
+        self.rate = rate.map(|(rate, burst)| (rate, burst as f64));
+        self
+    }
+
+    pub fn with_login_delay(mut self, max_delay: Option<Duration>) -> RateLimiter {
+        self.max_login_delay = max_delay;
+        self
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let (rate, burst) = self.rate.unwrap_or((0.0, 0.0));
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * rate).min(burst)
+    }
+
+    fn clients(&self, new_key: &str) -> MutexGuard<'_, HashMap<String, Client>> {
+        // Half of the clients are dropped at once, so the map is not searched for every new key
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_CLIENTS && !clients.contains_key(new_key) {
+            let mut updated: Vec<(Option<Instant>, String)> = clients.iter()
+                .map(|(key, client)| (last_updated(client), key.clone()))
+                .collect();
+            updated.select_nth_unstable(MAX_CLIENTS / 2);
+            for (_, key) in &updated[..MAX_CLIENTS / 2] {
+                clients.remove(key);
+            }
+        }
+        clients
+    }
+
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), u64> {
+        // Returns the number of seconds until the next request is allowed when the bucket is empty
+        let (rate, burst) = match self.rate {
+            Some(rate) => rate,
+            None => return Ok(())
+        };
+
+        let mut clients = self.clients(key);
+        let bucket = clients.entry(key.to_string()).or_default().bucket
+            .get_or_insert(Bucket{ tokens: burst, updated: now });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+
+    pub fn fail_login(&self, keys: &[String], now: Instant) -> Duration {
+        // Records a failure for every key and returns the delay earned by the earlier ones:
+        // none for the first failure, then 1s, 2s, 4s and so on up to the maximum
+        let max_delay = match self.max_login_delay {
+            Some(max_delay) => max_delay,
+            None => return Duration::from_secs(0)
+        };
+
+        let mut count = 0;
+        for key in keys {
+            let mut clients = self.clients(key);
+            let failures = clients.entry(key.clone()).or_default().failures
+                .get_or_insert(Failures{ count: 0, updated: now });
+            if !is_recent(failures, now) {
+                failures.count = 0;
+            }
+            count = count.max(failures.count);
+            failures.count = failures.count.saturating_add(1);
+            failures.updated = now;
+        }
+        match count {
+            0 => Duration::from_secs(0),
+            count => Duration::from_secs(1u64 << (count - 1).min(32)).min(max_delay)
+        }
+    }
+
+    pub fn succeed_login(&self, keys: &[String]) {
+        let mut clients = self.clients.lock().unwrap();
+        for key in keys {
+            if let Some(client) = clients.get_mut(key) {
+                client.failures = None;
+            }
+        }
+    }
+}
+
+fn last_updated(client: &Client) -> Option<Instant> {
+    let bucket = client.bucket.as_ref().map(|bucket| bucket.updated);
+    let failures = client.failures.as_ref().map(|failures| failures.updated);
+    bucket.max(failures)
+}
+
+fn is_recent(failures: &Failures, now: Instant) -> bool {
+    now.saturating_duration_since(failures.updated) < FAILURE_WINDOW
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{UserLimiter, RateLimiter};
+
+    #[test]
+    fn test_limiting_per_key() {
+        let limiter = UserLimiter::new(Some(2));
+        let first = limiter.acquire("user");
+        let second = limiter.acquire("user");
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.acquire("user").is_none());
+        assert!(limiter.acquire("other").is_some());
+
+        drop(first);
+        assert!(limiter.acquire("user").is_some());
+        drop(second);
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limiter = UserLimiter::new(None);
+        let permits: Vec<_> = (0..100).map(|_| limiter.acquire("user")).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_rate_per_key() {
+        let limiter = RateLimiter::default().with_rate(Some((2.0, 3)));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check("user", now), Ok(()));
+        }
+        assert_eq!(limiter.check("user", now), Err(1));
+        assert_eq!(limiter.check("other", now), Ok(()));
+
+        assert_eq!(limiter.check("user", now + Duration::from_millis(500)), Ok(()));
+        assert_eq!(limiter.check("user", now + Duration::from_millis(500)), Err(1));
+        for _ in 0..3 {
+            assert_eq!(limiter.check("user", now + Duration::from_secs(10)), Ok(()));
+        }
+        assert!(limiter.check("user", now + Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_slow_rate_retry_after() {
+        let limiter = RateLimiter::default().with_rate(Some((0.1, 1)));
+        let now = Instant::now();
+        assert_eq!(limiter.check("user", now), Ok(()));
+        assert_eq!(limiter.check("user", now), Err(10));
+        assert_eq!(limiter.check("user", now + Duration::from_secs(4)), Err(6));
+    }
+
+    #[test]
+    fn test_forgetting_oldest_clients() {
+        let limiter = RateLimiter::default().with_rate(Some((1.0, 1)));
+        let now = Instant::now();
+        for index in 0..super::MAX_CLIENTS {
+            assert_eq!(limiter.check(&index.to_string(), now + Duration::from_millis(index as u64)), Ok(()));
+        }
+        assert_eq!(limiter.check("0", now + Duration::from_secs(5)), Ok(()));
+        assert_eq!(limiter.clients.lock().unwrap().len(), super::MAX_CLIENTS);
+
+        assert_eq!(limiter.check("user", now + Duration::from_secs(5)), Ok(()));
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.len(), super::MAX_CLIENTS / 2 + 1);
+        assert!(clients.contains_key("0") && clients.contains_key("user"));
+        assert!(!clients.contains_key("1"));
+        assert!(clients.contains_key(&(super::MAX_CLIENTS - 1).to_string()));
+    }
+
+    #[test]
+    fn test_no_rate() {
+        let limiter = RateLimiter::default().with_rate(None);
+        let now = Instant::now();
+        assert!((0..100).all(|_| limiter.check("user", now).is_ok()));
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn test_login_delay() {
+        let delay = RateLimiter::default().with_login_delay(Some(Duration::from_secs(5)));
+        let now = Instant::now();
+        let delays: Vec<u64> = (0..6)
+            .map(|_| delay.fail_login(&keys(&["127.0.0.1", "finn"]), now).as_secs())
+            .collect();
+        assert_eq!(delays, vec![0, 1, 2, 4, 5, 5]);
+
+        // The address has already failed, so other usernames are slowed down as well
+        assert_eq!(delay.fail_login(&keys(&["127.0.0.1", "jake"]), now).as_secs(), 5);
+        assert_eq!(delay.fail_login(&keys(&["127.0.0.2", "jake"]), now).as_secs(), 1);
+        assert_eq!(delay.fail_login(&keys(&["127.0.0.3"]), now).as_secs(), 0);
+    }
+
+    #[test]
+    fn test_login_delay_reset() {
+        let delay = RateLimiter::default().with_login_delay(Some(Duration::from_secs(5)));
+        let now = Instant::now();
+        let user = keys(&["finn"]);
+        for _ in 0..3 {
+            delay.fail_login(&user, now);
+        }
+        assert_eq!(delay.fail_login(&user, now + Duration::from_secs(20 * 60)).as_secs(), 0);
+
+        delay.fail_login(&user, now + Duration::from_secs(20 * 60));
+        delay.succeed_login(&user);
+        assert_eq!(delay.fail_login(&user, now + Duration::from_secs(20 * 60)).as_secs(), 0);
+    }
+
+    #[test]
+    fn test_login_failures_share_buckets() {
+        let limiter = RateLimiter::default().with_rate(Some((1.0, 1))).with_login_delay(Some(Duration::from_secs(5)));
+        let now = Instant::now();
+        limiter.fail_login(&keys(&["finn"]), now);
+        assert_eq!(limiter.check("finn", now), Ok(()));
+        assert_eq!(limiter.clients.lock().unwrap().len(), 1);
+
+        // A failed login counts as an update, so the client outlives those seen before it
+        for index in 1..super::MAX_CLIENTS {
+            limiter.check(&index.to_string(), now).unwrap();
+        }
+        assert_eq!(limiter.fail_login(&keys(&["finn"]), now + Duration::from_secs(1)).as_secs(), 1);
+        limiter.check("jake", now + Duration::from_secs(5)).unwrap();
+        assert_eq!(limiter.clients.lock().unwrap().len(), super::MAX_CLIENTS / 2 + 1);
+        assert_eq!(limiter.fail_login(&keys(&["finn"]), now + Duration::from_secs(5)).as_secs(), 2);
+    }
+
+    #[test]
+    fn test_no_login_delay() {
+        let delay = RateLimiter::default();
+        let now = Instant::now();
+        assert!((0..10).all(|_| delay.fail_login(&keys(&["finn"]), now).as_secs() == 0));
+    }
+}
@@ -1,21 +1,30 @@
 use std::sync::Arc;
-use hyper::{Server};
-use hyper::service::{service_fn, make_service_fn};
-use hyper::server::conn::AddrStream;
-use std::convert::Infallible;
-use hyper::{Body, Request};
+use std::io::{self, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process;
 use futures::future::FutureExt;
 use clap::{App, load_yaml, ArgMatches, crate_authors, crate_version};
 use rand::prelude::*;
-use rand::distributions::{Alphanumeric};
 use rand_chacha::ChaCha20Rng;
+use tokio::runtime::{self, Runtime};
 
+#[macro_use]
+mod output;
 mod auth;
 mod proxy;
 mod config;
 use config::{ProxyConfig};
 mod service;
 mod credentials;
+mod startup;
+mod server;
+mod cors;
+mod compression;
+mod audit;
+mod limit;
+use credentials::{UserCredentials, CredentialsStore};
+use output::Verbosity;
 
 
 async fn run_reverse_proxy<'a>(matches: &'a ArgMatches<'a>) {
@@ -27,35 +36,289 @@ async fn run_reverse_proxy<'a>(matches: &'a ArgMatches<'a>) {
         }
     };
 
+    let upstream_checks = if matches.is_present("no-startup-check") {
+        None
+    } else {
+        Some(startup::check_upstreams(&config).await)
+    };
+    for line in startup::summary(&config, upstream_checks.as_deref()) {
+        info!("{}", line);
+    }
+    for (uri, _) in upstream_checks.iter().flatten().filter(|(_, check)| check.is_err()) {
+        config.set_upstream_available(uri, false);
+    }
+
     let config_arc = Arc::new(config);
-    let config_copy = config_arc.clone();
+    if config_arc.users_file().is_some() {
+        reload_users_on_hangup(config_arc.clone());
+    }
+
+    toggle_maintenance_on_signal(config_arc.clone());
 
-    let listener_service = move |_socket: &AddrStream| {
-        let config_arc = Arc::clone(&config_arc);
-        async move {
-            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
-                let config_arc = Arc::clone(&config_arc);
-                service::handle(request, config_arc).map(Ok::<_, Infallible>)
-            }))
+    let server = match server::serve(config_arc.clone(), shutdown_signal()) {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!("Cannot start the server: {}", error);
+            process::exit(1);
         }
     };
-
-    let server = Server::bind(&config_copy.socket_addr())
-        .serve(make_service_fn(listener_service));
+    // Connections are only opened once the port is ours, and requests are served meanwhile
+    tokio::spawn(async move {
+        for error in startup::warm_connections(&config_arc).await {
+            eprintln!("Cannot open a connection to the wiki server: {}", error);
+        }
+    });
     if let Err(e) = server.await {
         eprintln!("server error: {}", e);
     }
 }
 
-fn generate_secret(){
-    let mut secret = [0u8; 32];
+fn validate_config(matches: &ArgMatches) -> Result<Vec<String>, String> {
+    match ProxyConfig::from_args(matches) {
+        Ok(config) => Ok(startup::summary(&config, None)),
+        Err((option, error)) => Err(format!("Invalid value for --{}: {}", option, error))
+    }
+}
+
+fn check_config(matches: &ArgMatches) -> bool {
+    match validate_config(matches) {
+        Ok(summary) => {
+            println!("Configuration OK");
+            for line in summary {
+                println!("{}", line);
+            }
+            true
+        },
+        Err(error) => {
+            eprintln!("{}", error);
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            futures::select! {
+                _ = tokio::signal::ctrl_c().fuse() => {},
+                _ = terminate.recv().fuse() => {}
+            }
+        },
+        Err(_) => {
+            tokio::signal::ctrl_c().await.ok();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c().await.ok();
+}
+
+#[cfg(unix)]
+fn reload_users_on_hangup(config: Arc<ProxyConfig>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(error) => {
+            eprintln!("Cannot install SIGHUP handler: {}", error);
+            return
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match config.reload_users() {
+                Ok(count) => info!("Reloaded credentials for {} user(s)", count),
+                Err(error) => eprintln!("Cannot reload users: {}", error)
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_users_on_hangup(_config: Arc<ProxyConfig>) {}
+
+#[cfg(unix)]
+fn toggle_maintenance_on_signal(config: Arc<ProxyConfig>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut user_signal = match signal(SignalKind::user_defined2()) {
+        Ok(user_signal) => user_signal,
+        Err(error) => {
+            eprintln!("Cannot install SIGUSR2 handler: {}", error);
+            return
+        }
+    };
+
+    tokio::spawn(async move {
+        while user_signal.recv().await.is_some() {
+            let maintenance = !config.maintenance();
+            config.set_maintenance(maintenance);
+            if maintenance {
+                info!("Maintenance mode enabled, the wiki is read-only");
+            } else {
+                info!("Maintenance mode disabled");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn toggle_maintenance_on_signal(_config: Arc<ProxyConfig>) {}
+
+#[derive(Debug, PartialEq)]
+enum SecretFormat {
+    Hex,
+    Base64
+}
+
+impl SecretFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            SecretFormat::Hex => "hex",
+            SecretFormat::Base64 => "base64"
+        }
+    }
+}
+
+fn parse_secret_format(value: &str) -> Result<SecretFormat, String> {
+    match value {
+        "hex" => Ok(SecretFormat::Hex),
+        "base64" => Ok(SecretFormat::Base64),
+        _ => Err(format!("Unknown format: {}", value))
+    }
+}
+
+fn parse_secret_length(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(length) if (16..=64).contains(&length) => Ok(length),
+        Ok(_) => Err("The number of bytes must be between 16 and 64".to_string()),
+        Err(_) => Err("Invalid number of bytes".to_string())
+    }
+}
+
+fn encode_secret(secret: &[u8], format: &SecretFormat) -> String {
+    match format {
+        SecretFormat::Hex => {
+            let mut result = String::with_capacity(secret.len() * 2);
+            for byte in secret.iter() {
+                result.push_str(&format!("{:02X}", byte));
+            }
+            result
+        },
+        SecretFormat::Base64 => {
+            let config = base64::Config::new(base64::CharacterSet::Standard, false);
+            base64::encode_config(secret, config)
+        }
+    }
+}
+
+fn generate_secret(length: usize, format: &SecretFormat) -> String {
+    let mut secret = vec![0u8; length];
     let mut rng = ChaCha20Rng::from_entropy();
-    rng.fill(&mut secret);
+    rng.fill(&mut secret[..]);
+    encode_secret(&secret, format)
+}
 
-    for byte in secret.iter() {
-        print!("{:02X}", byte);
+fn print_secret<'a>(matches: &'a ArgMatches<'a>) {
+    let format = match matches.value_of("format").map(parse_secret_format) {
+        None => SecretFormat::Hex,
+        Some(Ok(format)) => format,
+        Some(Err(error)) => {
+            eprintln!("Invalid value for --format: {}", error);
+            return
+        }
+    };
+
+    let length = match matches.value_of("bytes").map(parse_secret_length) {
+        None => 32,
+        Some(Ok(length)) => length,
+        Some(Err(error)) => {
+            eprintln!("Invalid value for --bytes: {}", error);
+            return
+        }
+    };
+
+    let secret = generate_secret(length, &format);
+    if matches.is_present("json") {
+        println!("{}", secret_json(&secret, &format));
+    } else {
+        println!("{}", secret);
+    }
+}
+
+fn secret_json(secret: &str, format: &SecretFormat) -> String {
+    serde_json::json!({"secret": secret, "format": format.name()}).to_string()
+}
+
+fn read_password<R: Read>(mut reader: R) -> Result<String, String> {
+    let mut password = String::new();
+    if reader.read_to_string(&mut password).is_err() {
+        return Err("Cannot read password".to_string());
+    }
+
+    if password.ends_with('\n') {
+        password.pop();
+        if password.ends_with('\r') {
+            password.pop();
+        }
+    }
+    Ok(password)
+}
+
+fn obtain_password<'a>(matches: &'a ArgMatches<'a>) -> Result<String, String> {
+    let password = if matches.is_present("password-stdin") {
+        read_password(io::stdin())?
+    } else if let Some(path) = matches.value_of("password-file") {
+        match File::open(path) {
+            Ok(file) => read_password(file)?,
+            Err(error) => return Err(format!("Cannot open {}: {}", path, error))
+        }
+    } else {
+        match rpassword::prompt_password_stderr("Password: ") {
+            Ok(password) => password,
+            Err(_) => return Err("Cannot read password".to_string())
+        }
+    };
+
+    if password.is_empty() {
+        return Err("Password cannot be empty".to_string());
+    }
+    Ok(password)
+}
+
+fn parse_salt_length(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(length) if (config::MIN_SALT_LENGTH..=128).contains(&length) => Ok(length),
+        Ok(_) => Err(format!("The salt length must be between {} and 128", config::MIN_SALT_LENGTH)),
+        Err(_) => Err("Invalid salt length".to_string())
+    }
+}
+
+fn make_hash(password: &str, salt_length: usize, pepper: Option<&str>) -> (String, String) {
+    let salt = credentials::random_salt(salt_length);
+
+    let mut hash = String::with_capacity(64);
+    for byte in credentials::generate_hash(&salt, password, pepper) {
+        hash.push_str(&format!("{:02X}", byte));
     }
-    println!("");
+    (salt, hash)
+}
+
+fn make_credential(username: &str, password: &str, salt_length: usize, pepper: Option<&str>) -> String {
+    let (salt, hash) = make_hash(password, salt_length, pepper);
+    format!("{}:{}:{}", username, salt, hash)
+}
+
+fn credential_json(username: &str, salt: &str, hash: &str) -> String {
+    // A user without a name is written as null
+    let username = if username.is_empty() { None } else { Some(username) };
+    serde_json::json!({"username": username, "salt": salt, "hash": hash}).to_string()
 }
 
 fn create_user_credential<'a>(matches: &'a ArgMatches<'a>) {
@@ -68,38 +331,561 @@ fn create_user_credential<'a>(matches: &'a ArgMatches<'a>) {
         }
     };
 
-    let password = match rpassword::prompt_password_stderr("Password: ") {
+    let salt_length = match matches.value_of("salt-length").map(parse_salt_length) {
+        None => credentials::DEFAULT_SALT_LENGTH,
+        Some(Ok(length)) => length,
+        Some(Err(error)) => {
+            eprintln!("Invalid value for --salt-length: {}", error);
+            return
+        }
+    };
+
+    let pepper = match obtain_pepper(matches) {
+        Ok(pepper) => pepper,
+        Err(error) => {
+            eprintln!("{}", error);
+            return
+        }
+    };
+
+    let password = match obtain_password(matches) {
         Ok(password) => password,
-        Err(_) => {
-            eprintln!("Cannot read password");
+        Err(error) => {
+            eprintln!("{}", error);
             return
         }
     };
 
-    let rng = ChaCha20Rng::from_entropy();
-    let salt: String = rng.sample_iter(Alphanumeric).take(7).collect();
+    if matches.is_present("json") {
+        let (salt, hash) = make_hash(&password, salt_length, pepper.as_deref());
+        println!("{}", credential_json(&username, &salt, &hash));
+    } else if matches.is_present("hash-only") {
+        let (salt, hash) = make_hash(&password, salt_length, pepper.as_deref());
+        println!("{}\n{}", hash, salt);
+    } else {
+        println!("{}", make_credential(&username, &password, salt_length, pepper.as_deref()));
+    }
+}
 
-    let mut hash = String::with_capacity(64);
-    for byte in credentials::generate_hash(&salt, &password) {
-        hash.push_str(&format!("{:02X}", byte));
+struct SingleUserStore<'a> {
+    username: Option<String>,
+    credentials: UserCredentials,
+    pepper: Option<&'a str>
+}
+
+impl CredentialsStore for SingleUserStore<'_> {
+    fn credentials_for(&self, name: Option<&str>) -> Option<UserCredentials> {
+        if name == self.username.as_deref() {
+            Some(self.credentials.clone())
+        } else {
+            None
+        }
+    }
+
+    fn pepper(&self) -> Option<&str> {
+        self.pepper
+    }
+}
+
+fn obtain_pepper<'a>(matches: &'a ArgMatches<'a>) -> Result<Option<String>, String> {
+    matches.value_of("pepper").map(config::parse_pepper).transpose()
+        .map_err(|error| format!("Invalid value for --pepper: {}", error))
+}
+
+fn verify_credential(credential: &str, password: &str, pepper: Option<&str>) -> Result<bool, String> {
+    let mut users = config::parse_credentials(credential)?;
+    if users.len() != 1 {
+        return Err("Exactly one credential is expected".to_string());
+    }
+
+    let (username, credentials) = users.remove(0);
+    let store = SingleUserStore{ username: username.clone(), credentials, pepper };
+    Ok(store.can_login(username.as_deref(), password))
+}
+
+fn check_user_credential<'a>(matches: &'a ArgMatches<'a>) -> bool {
+    let pepper = match obtain_pepper(matches) {
+        Ok(pepper) => pepper,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false
+        }
+    };
+
+    let password = match obtain_password(matches) {
+        Ok(password) => password,
+        Err(error) => {
+            eprintln!("{}", error);
+            return false
+        }
+    };
+
+    match verify_credential(matches.value_of("credential").unwrap(), &password, pepper.as_deref()) {
+        Ok(true) => {
+            println!("The password matches the credential");
+            true
+        },
+        Ok(false) => {
+            println!("The password does not match the credential");
+            false
+        },
+        Err(error) => {
+            eprintln!("Invalid credential: {}", error);
+            false
+        }
     }
+}
+
+fn prompt_line(prompt: &str) -> Result<String, String> {
+    eprint!("{}", prompt);
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(_) => Ok(line.trim().to_string()),
+        Err(_) => Err("Cannot read the standard input".to_string())
+    }
+}
 
-    println!("{}:{}:{}", username, salt, hash);
+fn write_private_file(path: &Path, content: &str) -> Result<(), String> {
+    // Existing files are never overwritten, they may hold a secret that is already in use
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+        .and_then(|mut file| file.write_all(content.as_bytes()))
+        .map_err(|error| format!("Cannot write {}: {}", path.display(), error))
+}
+
+fn write_init_files(directory: &Path, username: &str, password: &str) -> Result<(PathBuf, PathBuf), String> {
+    let secret_path = directory.join("secret");
+    let users_path = directory.join("users");
+    let credential = make_credential(username, password, credentials::DEFAULT_SALT_LENGTH, None);
+    write_private_file(&secret_path, &format!("{}\n", generate_secret(32, &SecretFormat::Hex)))?;
+    write_private_file(&users_path, &format!("{}\n", credential))?;
+    Ok((secret_path, users_path))
+}
 
+fn run_command(wiki_url: &str, secret_path: &Path, users_path: &Path) -> String {
+    format!(
+        "tiddlyproxy run --wiki-url {} --secret-file {} --users-file {}",
+        wiki_url, secret_path.display(), users_path.display()
+    )
 }
 
-#[tokio::main]
-async fn main() {
+fn init_config<'a>(matches: &'a ArgMatches<'a>) -> Result<String, String> {
+    let wiki_url = match matches.value_of("wiki-url") {
+        Some(wiki_url) => wiki_url.to_string(),
+        None => prompt_line("Wiki URL: ")?
+    };
+    config::parse_wiki_uri(&wiki_url)
+        .map_err(|error| format!("Invalid wiki URL: {}", error))?;
+
+    let username = match matches.value_of("username") {
+        Some(username) => username.to_string(),
+        None => prompt_line("Username: ")?
+    };
+    let username = config::parse_username(&username)
+        .map_err(|error| format!("Invalid username: {}", error))?;
+    let password = obtain_password(matches)?;
+
+    let directory = Path::new(matches.value_of("output").unwrap_or("."));
+    let (secret_path, users_path) = write_init_files(directory, &username, &password)?;
+    Ok(run_command(&wiki_url, &secret_path, &users_path))
+}
+
+fn parse_worker_threads(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(threads) if (1..=256).contains(&threads) => Ok(threads),
+        Ok(_) => Err("The number of worker threads must be between 1 and 256".to_string()),
+        Err(_) => Err("Invalid number of worker threads".to_string())
+    }
+}
+
+fn build_runtime(worker_threads: Option<usize>) -> io::Result<Runtime> {
+    // A single thread runs everything on the main thread, otherwise one worker per CPU by default
+    let mut builder = runtime::Builder::new();
+    match worker_threads {
+        Some(1) => builder.basic_scheduler(),
+        Some(threads) => builder.threaded_scheduler().core_threads(threads),
+        None => builder.threaded_scheduler()
+    };
+    builder.enable_all().build()
+}
+
+fn run_server<'a>(matches: &'a ArgMatches<'a>) {
+    let worker_threads = match matches.value_of("worker-threads").map(parse_worker_threads) {
+        None => None,
+        Some(Ok(threads)) => Some(threads),
+        Some(Err(error)) => {
+            eprintln!("Invalid value for --worker-threads: {}", error);
+            process::exit(1);
+        }
+    };
+
+    match build_runtime(worker_threads) {
+        Ok(mut runtime) => runtime.block_on(run_reverse_proxy(matches)),
+        Err(error) => {
+            eprintln!("Cannot start the runtime: {}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn verbosity<'a>(matches: &'a ArgMatches<'a>) -> Verbosity {
+    if matches.is_present("quiet") {
+        Verbosity::Quiet
+    } else if matches.is_present("verbose") {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    }
+}
+
+fn main() {
     let args_config = load_yaml!("../data/arguments.yml");
     let options = App::from(args_config)
         .version(crate_version!())
         .author(crate_authors!())
         .get_matches();
 
+    // The flags are global, so they may follow the subcommand's name
+    let matches = options.subcommand().1.unwrap_or(&options);
+    output::set_verbosity(verbosity(matches));
+    match output::parse_log_format(matches.value_of("log-format").unwrap_or("text")) {
+        Ok(format) => output::set_log_format(format),
+        Err(error) => {
+            eprintln!("Invalid value for --log-format: {}", error);
+            process::exit(1);
+        }
+    }
+
     match options.subcommand() {
-        ("run", Some(matches)) => run_reverse_proxy(matches).await,
-        ("gensecret", _) => generate_secret(),
+        ("run", Some(matches)) => run_server(matches),
+        ("checkconfig", Some(matches)) if !check_config(matches) => process::exit(1),
+        ("gensecret", Some(matches)) => print_secret(matches),
         ("mkuser", Some(matches)) => create_user_credential(matches),
+        ("checkuser", Some(matches)) if !check_user_credential(matches) => process::exit(1),
+        ("init", Some(matches)) => match init_config(matches) {
+            Ok(command) => println!("Configuration created, start the proxy with:\n{}", command),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+        },
         _ => {}
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    mod test_generating_secret {
+        use rstest::rstest;
+        use super::super::{generate_secret, parse_secret_length, secret_json, SecretFormat};
+
+        #[test]
+        fn test_default_hex_secret() {
+            let secret = generate_secret(32, &SecretFormat::Hex);
+            assert_eq!(secret.len(), 64);
+            assert!(secret.chars().all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c)));
+        }
+
+        #[test]
+        fn test_base64_secret() {
+            let secret = generate_secret(48, &SecretFormat::Base64);
+            assert_eq!(secret.len(), 64);
+            assert!(secret.chars().all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/'));
+
+            let config = base64::Config::new(base64::CharacterSet::Standard, false);
+            assert_eq!(base64::decode_config(&secret, config).unwrap().len(), 48);
+        }
+
+        #[rstest(format, name,
+            case(SecretFormat::Hex, "hex"),
+            case(SecretFormat::Base64, "base64"),
+        )]
+        fn test_secret_json(format: SecretFormat, name: &str) {
+            let secret = generate_secret(32, &format);
+            let json: serde_json::Value = serde_json::from_str(&secret_json(&secret, &format)).unwrap();
+            assert_eq!(json["secret"], secret.as_str());
+            assert_eq!(json["format"], name);
+        }
+
+        #[rstest(value, expected,
+            case("16", Ok(16)),
+            case("64", Ok(64)),
+            case("15", Err("The number of bytes must be between 16 and 64".to_string())),
+            case("65", Err("The number of bytes must be between 16 and 64".to_string())),
+            case("abc", Err("Invalid number of bytes".to_string()))
+        )]
+        fn test_parse_secret_length(value: &str, expected: Result<usize, String>) {
+            assert_eq!(parse_secret_length(value), expected);
+        }
+    }
+
+    mod test_creating_credentials {
+        use std::io::Cursor;
+        use rstest::rstest;
+        use super::super::{
+            read_password, make_credential, make_hash, credential_json, parse_salt_length, verify_credential
+        };
+        use crate::config::parse_credentials;
+        use crate::config::ProxyConfig;
+        use crate::credentials::CredentialsStore;
+
+        #[test]
+        fn test_read_password_trims_single_newline() {
+            assert_eq!(read_password(Cursor::new("password\n")), Ok("password".to_string()));
+            assert_eq!(read_password(Cursor::new("password\r\n")), Ok("password".to_string()));
+            assert_eq!(read_password(Cursor::new("password\n\n")), Ok("password\n".to_string()));
+            assert_eq!(read_password(Cursor::new(" pass ")), Ok(" pass ".to_string()));
+        }
+
+        #[test]
+        fn test_credential_from_stdin_password() {
+            let password = read_password(Cursor::new("secret password\n")).unwrap();
+            let credential = make_credential("user", &password, 7, None);
+
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &credential, None, None
+            ).unwrap();
+            assert!(config.can_login(Some("user"), "secret password"));
+            assert!(!config.can_login(Some("user"), "secret password\n"));
+        }
+
+        #[test]
+        fn test_credential_with_salt_length() {
+            let credential = make_credential("user", "password", 32, None);
+            assert_eq!(credential.split(':').nth(1).unwrap().len(), 32);
+
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &credential, None, None
+            ).unwrap();
+            assert!(config.can_login(Some("user"), "password"));
+        }
+
+        #[test]
+        fn test_hash_only() {
+            let (salt, hash) = make_hash("password", 7, None);
+            assert_eq!(salt.len(), 7);
+            assert_eq!(hash.len(), 64);
+            assert!(hash.chars().all(|ch| ch.is_ascii_hexdigit()));
+
+            let credential = format!("user:{}:{}", salt, hash);
+            assert_eq!(verify_credential(&credential, "password", None), Ok(true));
+            assert_eq!(verify_credential(&credential, "wrong", None), Ok(false));
+        }
+
+        #[rstest(username, expected_username,
+            case("user", Some("user")),
+            case("", None),
+        )]
+        fn test_credential_json(username: &str, expected_username: Option<&str>) {
+            let (salt, hash) = make_hash("password", 7, None);
+            let json: serde_json::Value = serde_json::from_str(&credential_json(username, &salt, &hash)).unwrap();
+            assert_eq!(json["username"].as_str(), expected_username);
+
+            let credential = format!(
+                "{}:{}:{}",
+                json["username"].as_str().unwrap_or(""),
+                json["salt"].as_str().unwrap(),
+                json["hash"].as_str().unwrap()
+            );
+            let users = parse_credentials(&credential).unwrap();
+            assert_eq!(users[0].0.as_deref(), expected_username);
+            assert_eq!(verify_credential(&credential, "password", None), Ok(true));
+        }
+
+        #[test]
+        fn test_peppered_credential() {
+            let credential = make_credential("user", "password", 7, Some("pepper"));
+            assert_eq!(verify_credential(&credential, "password", Some("pepper")), Ok(true));
+            assert_eq!(verify_credential(&credential, "password", Some("other")), Ok(false));
+            assert_eq!(verify_credential(&credential, "password", None), Ok(false));
+
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                &credential, None, None
+            ).unwrap();
+            assert!(!config.can_login(Some("user"), "password"));
+            let config = config.with_pepper(Some("pepper".to_string()));
+            assert!(config.can_login(Some("user"), "password"));
+            assert!(!config.can_login(Some("user"), "wrong"));
+        }
+
+        #[rstest(value, expected,
+            case("5", Ok(5)),
+            case("128", Ok(128)),
+            case("4", Err("The salt length must be between 5 and 128".to_string())),
+            case("129", Err("The salt length must be between 5 and 128".to_string())),
+            case("long", Err("Invalid salt length".to_string()))
+        )]
+        fn test_parse_salt_length(value: &str, expected: Result<usize, String>) {
+            assert_eq!(parse_salt_length(value), expected);
+        }
+    }
+
+    mod test_verifying_credentials {
+        use super::super::verify_credential;
+
+        #[test]
+        fn test_matching_password() {
+            assert_eq!(verify_credential(
+                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "password",
+                None
+            ), Ok(true));
+        }
+
+        #[test]
+        fn test_matching_password_without_username() {
+            assert_eq!(verify_credential(
+                ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "password",
+                None
+            ), Ok(true));
+        }
+
+        #[test]
+        fn test_wrong_password() {
+            assert_eq!(verify_credential(
+                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "wrong",
+                None
+            ), Ok(false));
+        }
+
+        #[test]
+        fn test_malformed_credential() {
+            assert_eq!(
+                verify_credential("user:ABCDEF", "password", None),
+                Err("Wrong number of components".to_string())
+            );
+        }
+    }
+
+    mod test_runtime {
+        use std::thread;
+        use rstest::rstest;
+        use super::super::{build_runtime, parse_worker_threads};
+
+        #[rstest(worker_threads, same_thread,
+            case(Some(1), true),
+            case(Some(2), false),
+            case(None, false),
+        )]
+        fn test_building_runtime(worker_threads: Option<usize>, same_thread: bool) {
+            let mut runtime = build_runtime(worker_threads).unwrap();
+            let current = thread::current().id();
+            let task_thread = runtime.block_on(async {
+                tokio::spawn(async { thread::current().id() }).await.unwrap()
+            });
+            assert_eq!(task_thread == current, same_thread);
+        }
+
+        #[rstest(value, expected,
+            case("1", Ok(1)),
+            case("256", Ok(256)),
+            case("0", Err("The number of worker threads must be between 1 and 256".to_string())),
+            case("many", Err("Invalid number of worker threads".to_string()))
+        )]
+        fn test_parse_worker_threads(value: &str, expected: Result<usize, String>) {
+            assert_eq!(parse_worker_threads(value), expected);
+        }
+    }
+
+    mod test_init {
+        use std::fs;
+        use clap::{App, load_yaml};
+        use crate::config::ProxyConfig;
+        use crate::credentials::CredentialsStore;
+        use super::super::{write_init_files, run_command};
+
+        #[test]
+        fn test_generated_config_loads() {
+            let directory = std::env::temp_dir().join(format!("tiddlyproxy-init-{}", std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            let (secret_path, users_path) = write_init_files(&directory, "finn", "hunter2").unwrap();
+            let command = run_command("localhost:8080", &secret_path, &users_path);
+
+            let yaml = load_yaml!("../data/arguments.yml");
+            let matches = App::from_yaml(yaml).get_matches_from(command.split(' '));
+            let config = ProxyConfig::from_args(matches.subcommand_matches("run").unwrap());
+            let existing = write_init_files(&directory, "jake", "password");
+            fs::remove_dir_all(&directory).unwrap();
+
+            let config = config.unwrap();
+            assert!(config.can_login(Some("finn"), "hunter2"));
+            assert!(!config.can_login(Some("finn"), "password"));
+            assert!(existing.unwrap_err().starts_with("Cannot write"));
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn test_generated_files_are_private() {
+            use std::os::unix::fs::PermissionsExt;
+            let directory = std::env::temp_dir().join(format!("tiddlyproxy-init-mode-{}", std::process::id()));
+            fs::create_dir_all(&directory).unwrap();
+            let (secret_path, users_path) = write_init_files(&directory, "finn", "hunter2").unwrap();
+            let modes = [&secret_path, &users_path].iter()
+                .map(|path| fs::metadata(path).unwrap().permissions().mode() & 0o777)
+                .collect::<Vec<_>>();
+            fs::remove_dir_all(&directory).unwrap();
+            assert_eq!(modes, vec![0o600, 0o600]);
+        }
+    }
+
+    mod test_checking_config {
+        use clap::{App, load_yaml};
+        use super::super::validate_config;
+
+        fn check(args: &[&str]) -> Result<Vec<String>, String> {
+            let yaml = load_yaml!("../data/arguments.yml");
+            let matches = App::from_yaml(yaml).get_matches_from(
+                ["tiddlyproxy", "checkconfig", "--wiki-url", "localhost:8080"].iter().chain(args)
+            );
+            validate_config(matches.subcommand_matches("checkconfig").unwrap())
+        }
+
+        #[test]
+        fn test_valid_config() {
+            let summary = check(&[
+                "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "--port", "8000"
+            ]).unwrap();
+            assert!(summary.contains(&"Listening on: http://127.0.0.1:8000".to_string()));
+            assert!(summary.contains(&"Wiki server: http://localhost:8080/".to_string()));
+        }
+
+        #[test]
+        fn test_invalid_secret() {
+            assert_eq!(
+                check(&[
+                    "--secret", "00000000000000000000000000000000000000000000000000000000000000",
+                    "--users", "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+                ]),
+                Err("Invalid value for --secret: String is too short, 64 hex digits expected".to_string())
+            );
+        }
+
+        #[test]
+        fn test_invalid_credentials() {
+            assert_eq!(
+                check(&[
+                    "--secret", "59C2664A8467D3AE144141C27669EEE7EF1E8283E1EB82B110A3E9EF5D778DDC",
+                    "--users", "user:ABC:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+                ]),
+                Err("Invalid value for --users: The value for salt is too short".to_string())
+            );
+        }
+    }
+}
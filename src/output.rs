@@ -0,0 +1,193 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static LOG_FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Normal
+    }
+}
+
+pub fn set_log_format(format: LogFormat) {
+    LOG_FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn log_format() -> LogFormat {
+    match LOG_FORMAT.load(Ordering::Relaxed) {
+        1 => LogFormat::Json,
+        _ => LogFormat::Text
+    }
+}
+
+pub fn parse_log_format(value: &str) -> Result<LogFormat, String> {
+    match value.trim().to_lowercase().as_str() {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(format!("Unsupported log format: {}", value.trim()))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessEntry<'a> {
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: OffsetDateTime,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    // Unknown for streamed responses without a Content-Length
+    pub bytes: Option<u64>,
+    pub duration_ms: u64,
+    pub user: Option<&'a str>,
+    pub client_ip: IpAddr,
+    pub request_id: Option<&'a str>
+}
+
+fn serialize_timestamp<S: serde::Serializer>(time: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.format("%Y-%m-%dT%H:%M:%SZ"))
+}
+
+fn write_access<W: Write>(writer: &mut W, current: Verbosity, format: LogFormat, entry: &AccessEntry) {
+    // JSON lines are meant for log collectors and are written unless quiet, text ones only when verbose
+    match format {
+        LogFormat::Text => write_message(writer, current, Verbosity::Verbose, format_args!(
+            "{} {} {} {} {}", entry.client_ip, entry.method, entry.path, entry.status, entry.request_id.unwrap_or("-")
+        )),
+        LogFormat::Json => match serde_json::to_string(entry) {
+            Ok(line) => write_message(writer, current, Verbosity::Normal, format_args!("{}", line)),
+            Err(error) => eprintln!("Cannot write the access log: {}", error)
+        }
+    }
+}
+
+pub fn access(entry: &AccessEntry) {
+    write_access(&mut io::stderr(), verbosity(), log_format(), entry);
+}
+
+pub fn is_access_logged() -> bool {
+    match log_format() {
+        LogFormat::Text => verbosity() == Verbosity::Verbose,
+        LogFormat::Json => verbosity() != Verbosity::Quiet
+    }
+}
+
+fn write_message<W: Write>(writer: &mut W, current: Verbosity, level: Verbosity, message: fmt::Arguments) {
+    if level <= current {
+        writeln!(writer, "{}", message).ok();
+    }
+}
+
+pub fn message(level: Verbosity, message: fmt::Arguments) {
+    // Only informational messages go through here, errors and the commands' output are always printed
+    write_message(&mut io::stderr(), verbosity(), level, message);
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::output::message($crate::output::Verbosity::Normal, format_args!($($arg)*))
+    };
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use rstest::rstest;
+    use time::OffsetDateTime;
+    use super::{write_message, write_access, parse_log_format, AccessEntry, LogFormat, Verbosity};
+
+    #[rstest(current, level, written,
+        case(Verbosity::Quiet, Verbosity::Normal, false),
+        case(Verbosity::Quiet, Verbosity::Verbose, false),
+        case(Verbosity::Normal, Verbosity::Normal, true),
+        case(Verbosity::Normal, Verbosity::Verbose, false),
+        case(Verbosity::Verbose, Verbosity::Normal, true),
+        case(Verbosity::Verbose, Verbosity::Verbose, true),
+    )]
+    fn test_write_message(current: Verbosity, level: Verbosity, written: bool) {
+        let mut output = Vec::new();
+        write_message(&mut output, current, level, format_args!("Listening on: {}", 8080));
+        let expected = if written { "Listening on: 8080\n" } else { "" };
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    fn access_entry() -> AccessEntry<'static> {
+        AccessEntry{
+            timestamp: OffsetDateTime::from_unix_timestamp(1600000000),
+            method: "GET",
+            path: "/recipes/default/tiddlers.json",
+            status: 200,
+            bytes: Some(1234),
+            duration_ms: 15,
+            user: Some("finn"),
+            client_ip: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            request_id: Some("abc")
+        }
+    }
+
+    #[test]
+    fn test_json_access() {
+        let mut output = Vec::new();
+        write_access(&mut output, Verbosity::Normal, LogFormat::Json, &access_entry());
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 1);
+
+        let value: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value, serde_json::json!({
+            "timestamp": "2020-09-13T12:26:40Z",
+            "method": "GET",
+            "path": "/recipes/default/tiddlers.json",
+            "status": 200,
+            "bytes": 1234,
+            "duration_ms": 15,
+            "user": "finn",
+            "client_ip": "10.0.0.1",
+            "request_id": "abc"
+        }));
+    }
+
+    #[rstest(current, format, expected,
+        case(Verbosity::Normal, LogFormat::Text, ""),
+        case(Verbosity::Verbose, LogFormat::Text, "10.0.0.1 GET /recipes/default/tiddlers.json 200 abc\n"),
+        case(Verbosity::Quiet, LogFormat::Json, ""),
+    )]
+    fn test_access_verbosity(current: Verbosity, format: LogFormat, expected: &str) {
+        let mut output = Vec::new();
+        write_access(&mut output, current, format, &access_entry());
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[rstest(value, expected,
+        case("text", Ok(LogFormat::Text)),
+        case(" JSON ", Ok(LogFormat::Json)),
+        case("xml", Err("Unsupported log format: xml".to_string())),
+    )]
+    fn test_parse_log_format(value: &str, expected: Result<LogFormat, String>) {
+        assert_eq!(parse_log_format(value), expected);
+    }
+}
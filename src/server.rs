@@ -0,0 +1,616 @@
+use std::sync::Arc;
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use hyper::{Server, Body, Request, Response, StatusCode};
+use hyper::service::{service_fn, make_service_fn};
+use hyper::server::conn::{AddrStream, AddrIncoming};
+use hyper::server::Builder;
+use hyper::header::HeaderValue;
+use time::OffsetDateTime;
+use tokio::sync::Semaphore;
+use tokio::time::delay_for;
+use futures::future::{Either, FutureExt, join, join_all, select};
+use crate::config::ProxyConfig;
+use crate::service;
+use crate::proxy;
+use crate::output::{self, AccessEntry};
+
+
+fn guard_panics<F>(future: F) -> impl Future<Output=Response<Body>>
+        where F: Future<Output=Response<Body>> {
+    // A panic would otherwise drop the connection without any response
+    AssertUnwindSafe(future).catch_unwind().map(|result| match result {
+        Ok(response) => response,
+        Err(_) => {
+            eprintln!("Internal error while handling a request");
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .header("Content-Type", "text/plain")
+                .body(Body::from("Internal server error"))
+                .unwrap()
+        }
+    })
+}
+
+// Counts the request as in flight until it is answered or dropped
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn start(counter: &Arc<AtomicUsize>) -> InFlight {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn bind_error(addr: &SocketAddr, error: &hyper::Error) -> String {
+    let kind = error.source()
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .map(io::Error::kind);
+    match kind {
+        Some(io::ErrorKind::AddrInUse) =>
+            format!("address already in use: {} \u{2014} is another instance running?", addr),
+        Some(io::ErrorKind::PermissionDenied) =>
+            format!("permission denied: {} \u{2014} ports below 1024 usually require root privileges", addr),
+        Some(io::ErrorKind::AddrNotAvailable) =>
+            format!("address not available: {} \u{2014} is the host assigned to this machine?", addr),
+        _ => format!("cannot listen on {}: {}", addr, error)
+    }
+}
+
+fn bind(addr: &SocketAddr) -> Result<Builder<AddrIncoming>, String> {
+    Server::try_bind(addr).map_err(|error| bind_error(addr, &error))
+}
+
+pub fn serve<F>(config: Arc<ProxyConfig>, shutdown: F) -> Result<impl Future<Output=Result<(), hyper::Error>>, String>
+        where F: Future<Output=()> {
+    // All addresses are bound before serving, so that a failure is reported right away
+    let builders = config.socket_addrs().iter().map(bind).collect::<Result<Vec<_>, String>>()?;
+    let redirect_builders = config.http_redirect_addrs().iter().map(bind).collect::<Result<Vec<_>, String>>()?;
+    let shutdown = shutdown.shared();
+    // Shared by all addresses, requests over the limit wait for a permit
+    let limit = config.max_connections().map(|limit| Arc::new(Semaphore::new(limit)));
+    let in_flight = Arc::new(AtomicUsize::new(0));
+
+    let servers = builders.into_iter().map(|builder| {
+        let config_arc = config.clone();
+        let limit = limit.clone();
+        let in_flight = in_flight.clone();
+        let listener_service = move |socket: &AddrStream| {
+            let config_arc = Arc::clone(&config_arc);
+            let limit = limit.clone();
+            let in_flight = in_flight.clone();
+            let remote_addr = socket.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
+                    let config_arc = Arc::clone(&config_arc);
+                    request.extensions_mut().insert(remote_addr);
+                    let limit = limit.clone();
+                    let in_flight = InFlight::start(&in_flight);
+                    async move {
+                        let _in_flight = in_flight;
+                        let _permit = match &limit {
+                            Some(semaphore) => Some(semaphore.acquire().await),
+                            None => None
+                        };
+                        let keepalive = config_arc.client_keepalive();
+                        // The query is left out since it may contain tokens
+                        let summary = if output::is_access_logged() {
+                            let client_ip = proxy::client_ip(&request, config_arc.proxy_options().trusted_proxy);
+                            let client_ip = client_ip.unwrap_or_else(|| remote_addr.ip());
+                            Some((client_ip, request.method().to_string(), request.uri().path().to_string()))
+                        } else {
+                            None
+                        };
+                        let started = Instant::now();
+                        let mut response = guard_panics(service::handle(request, config_arc)).await;
+                        if let Some((client_ip, method, path)) = summary {
+                            let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+                            output::access(&AccessEntry{
+                                timestamp: OffsetDateTime::now_utc(),
+                                method: &method,
+                                path: &path,
+                                status: response.status().as_u16(),
+                                bytes: header("Content-Length").and_then(|length| length.parse().ok()),
+                                duration_ms: started.elapsed().as_millis() as u64,
+                                user: response.extensions().get::<service::AuthenticatedUser>()
+                                    .map(|user| user.0.as_str())
+                                    .filter(|user| !user.is_empty()),
+                                client_ip,
+                                request_id: header("X-Request-Id")
+                            });
+                        }
+                        // Hyper closes the connection without telling the client otherwise
+                        if !keepalive {
+                            response.headers_mut().insert("Connection", HeaderValue::from_static("close"));
+                        }
+                        response
+                    }.map(Ok::<_, Infallible>)
+                }))
+            }
+        };
+
+        builder
+            .tcp_nodelay(config.tcp_nodelay())
+            .tcp_keepalive(config.tcp_keepalive().map(Duration::from_secs))
+            .http1_keepalive(config.client_keepalive())
+            .serve(make_service_fn(listener_service))
+            .with_graceful_shutdown(shutdown.clone())
+    }).collect::<Vec<_>>();
+
+    let https_port = config.https_port();
+    let redirects = redirect_builders.into_iter().map(|builder| {
+        let redirect_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| async move {
+                Ok::<_, Infallible>(service::https_redirect(&request, https_port))
+            }))
+        });
+        builder
+            .serve(redirect_service)
+            .with_graceful_shutdown(shutdown.clone())
+    }).collect::<Vec<_>>();
+
+    let servers = join(join_all(servers), join_all(redirects))
+        .map(|(results, redirect_results)| results.into_iter().chain(redirect_results).collect());
+    let shutdown_timeout = config.shutdown_timeout();
+    Ok(async move {
+        let timeout = match shutdown_timeout {
+            Some(timeout) => timeout,
+            None => return servers.await
+        };
+        // Requests still running when the timeout elapses are dropped with the server
+        let deadline = shutdown.then(move |_| delay_for(Duration::from_secs(timeout)));
+        match select(Box::pin(servers), Box::pin(deadline)).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => {
+                eprintln!(
+                    "Shutdown timed out after {}s, dropping {} unfinished requests",
+                    timeout, in_flight.load(Ordering::SeqCst)
+                );
+                Ok(())
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::convert::Infallible;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use hyper::{Client, Server, Uri, Body, Request, Response};
+    use hyper::body::{Bytes, HttpBody};
+    use hyper::header::{HeaderMap, HeaderValue};
+    use hyper::service::{make_service_fn, service_fn};
+    use futures::channel::oneshot;
+    use futures::stream::StreamExt;
+    use crate::config::ProxyConfig;
+    use crate::auth::Token;
+    use crate::compression::Encoding;
+    use super::{serve, guard_panics};
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn listening_config(upstream: &str, port: u16) -> ProxyConfig {
+        ProxyConfig::from_values(
+            upstream,
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            Some("127.0.0.1"), Some(&port.to_string())
+        ).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serving_multiple_addresses() {
+        let ports = [free_port(), free_port()];
+        let config = ProxyConfig::from_values(
+            "localhost",
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            Some("127.0.0.1"), Some(&format!("{},{}", ports[0], ports[1]))
+        ).unwrap();
+        assert_eq!(config.socket_addrs().len(), 2);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let client = Client::new();
+        for port in ports.iter() {
+            let uri: Uri = format!("http://127.0.0.1:{}/hello", port).parse().unwrap();
+            let response = client.get(uri).await.unwrap();
+            assert_eq!(response.status(), 303);
+            assert_eq!(response.headers().get("Location").unwrap(), "/");
+        }
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_redirect_port() {
+        let ports = [free_port(), free_port()];
+        let config = listening_config("localhost", ports[0]).with_http_redirect_port(Some(ports[1]));
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let request = Request::builder()
+            .uri(format!("http://127.0.0.1:{}/recipes/default/tiddlers.json?filter=a%20b", ports[1]))
+            .header("Host", format!("wiki.example.com:{}", ports[1]))
+            .body(Body::empty())
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        assert_eq!(response.status(), 301);
+        assert_eq!(
+            response.headers().get("Location").unwrap(),
+            "https://wiki.example.com/recipes/default/tiddlers.json?filter=a%20b"
+        );
+
+        // The proxy itself is still served on its own port
+        let uri: Uri = format!("http://127.0.0.1:{}/hello", ports[0]).parse().unwrap();
+        assert_eq!(Client::new().get(uri).await.unwrap().status(), 303);
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_disabling_keepalive() {
+        let port = free_port();
+        let config = listening_config("localhost", port).with_client_keepalive(false);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let uri: Uri = format!("http://127.0.0.1:{}/hello", port).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), 303);
+        assert_eq!(response.headers().get("Connection").unwrap(), "close");
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options() {
+        // The options of accepted sockets cannot be read back, this checks they are applied without errors
+        let port = free_port();
+        let config = listening_config("localhost", port).with_tcp_options(true, Some(60));
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let uri: Uri = format!("http://127.0.0.1:{}/hello", port).parse().unwrap();
+        let response = Client::new().get(uri).await.unwrap();
+        assert_eq!(response.status(), 303);
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_guarding_panics() {
+        let response = guard_panics(async {
+            if true {
+                panic!("handler failure");
+            }
+            Response::new(Body::empty())
+        }).await;
+        assert_eq!(response.status(), 500);
+        let body = String::from_utf8(response.into_body()
+            .map(|c| c.unwrap().to_vec())
+            .concat().await).unwrap();
+        assert_eq!(body, "Internal server error");
+
+        let response = guard_panics(async { Response::new(Body::from("ok")) }).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_address_in_use() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = listening_config("localhost", port);
+
+        match serve(Arc::new(config), async {}) {
+            Ok(_) => panic!("The address should be in use"),
+            Err(error) => assert_eq!(
+                error,
+                format!("address already in use: 127.0.0.1:{} \u{2014} is another instance running?", port)
+            )
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_timeout() {
+        // The wiki server accepts the request but never answers it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let streams: Vec<_> = listener.incoming().collect();
+            drop(streams);
+        });
+
+        let port = free_port();
+        let config = listening_config(&upstream, port).with_shutdown_timeout(Some(1));
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let request = hyper::Request::get(format!("http://127.0.0.1:{}/hello", port))
+            .header("Cookie", format!("proxy_auth={}", token))
+            .body(Body::empty()).unwrap();
+        tokio::spawn(Client::new().request(request));
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        let started = Instant::now();
+        sender.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), server).await
+            .expect("The server should stop once the shutdown timeout elapses");
+        assert!(result.unwrap().is_ok());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    fn read_head(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> String {
+        // Reads until the end of the header section, leaving anything after it in the buffer
+        let mut chunk = [0u8; 4096];
+        loop {
+            if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buffer[..end]).to_string();
+                buffer.drain(..end + 4);
+                return head;
+            }
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "The connection was closed before the header section ended");
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn continue_server() -> (String, Arc<AtomicBool>) {
+        // Answers an upload with an interim 100 Continue before reading its body, then echoes it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let expected = Arc::new(AtomicBool::new(false));
+        let result = expected.clone();
+        thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let mut buffer = Vec::new();
+            let head = read_head(&mut stream, &mut buffer).to_lowercase();
+            expected.store(head.contains("\r\nexpect: 100-continue"), Ordering::SeqCst);
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").unwrap();
+
+            let mut chunk = [0u8; 4096];
+            while buffer.len() < 11 {
+                let read = stream.read(&mut chunk).unwrap();
+                buffer.extend_from_slice(&chunk[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\n").unwrap();
+            stream.write_all(&buffer).unwrap();
+        });
+        (address, result)
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue() {
+        let (upstream, expected) = continue_server();
+        let port = free_port();
+        let config = listening_config(&upstream, port);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let (interim, head, body, rejected) = tokio::task::spawn_blocking(move || {
+            let upload = |cookie: &str| format!(
+                "PUT /recipes/Bread HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: 11\r\n\
+                 Expect: 100-continue\r\nConnection: close\r\n\r\n",
+                cookie
+            );
+
+            // The body is only sent once the proxy asks for it
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut buffer = Vec::new();
+            stream.write_all(upload(&format!("Cookie: proxy_auth={}\r\n", token)).as_bytes()).unwrap();
+            let interim = read_head(&mut stream, &mut buffer);
+            stream.write_all(b"hello world").unwrap();
+            let head = read_head(&mut stream, &mut buffer);
+            stream.read_to_end(&mut buffer).unwrap();
+
+            // Unauthenticated uploads are turned away without reading the body
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            let mut rejected = Vec::new();
+            stream.write_all(upload("").as_bytes()).unwrap();
+            let rejected = read_head(&mut stream, &mut rejected);
+            (interim, head, String::from_utf8(buffer).unwrap(), rejected)
+        }).await.unwrap();
+
+        assert_eq!(interim, "HTTP/1.1 100 Continue");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(body, "hello world");
+        assert!(expected.load(Ordering::SeqCst));
+        assert!(rejected.starts_with("HTTP/1.1 303 See Other"));
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    // A body with trailers, hyper's own Body cannot be given any
+    struct TrailerBody {
+        data: Option<Bytes>,
+        trailers: Option<HeaderMap>
+    }
+
+    impl HttpBody for TrailerBody {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_data(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Result<Bytes, Infallible>>> {
+            Poll::Ready(self.data.take().map(Ok))
+        }
+
+        fn poll_trailers(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<Option<HeaderMap>, Infallible>> {
+            Poll::Ready(Ok(self.trailers.take()))
+        }
+    }
+
+    async fn trailer_server() -> String {
+        // An HTTP/2 server that echoes the request's body and trailers, adding a trailer of its own
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let service = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let mut body = request.into_body();
+                let mut data = Vec::new();
+                while let Some(chunk) = body.data().await {
+                    data.extend_from_slice(&chunk.unwrap());
+                }
+                let mut trailers = body.trailers().await.unwrap().unwrap_or_default();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                Ok::<_, Infallible>(Response::builder()
+                    .header("Content-Type", "text/plain")
+                    .header("Content-Length", data.len())
+                    .body(TrailerBody{ data: Some(Bytes::from(data)), trailers: Some(trailers) })
+                    .unwrap())
+            }))
+        });
+        let server = Server::from_tcp(listener).unwrap().http2_only(true).serve(service);
+        tokio::spawn(server);
+        address
+    }
+
+    #[tokio::test]
+    async fn test_forwarding_trailers() {
+        // Hyper only carries trailers over HTTP/2, so both sides of the proxy use it
+        let upstream = trailer_server().await;
+        let port = free_port();
+        let config = listening_config(&upstream, port)
+            .with_upstream_http2(true)
+            .with_compression(vec![Encoding::Gzip]);
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        // Without "TE: trailers" the response would be compressed, rebuilding its body without them
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://127.0.0.1:{}/stream", port))
+            .header("Cookie", format!("proxy_auth={}", token))
+            .header("TE", "trailers")
+            .header("Accept-Encoding", "gzip")
+            .body(TrailerBody{ data: Some(Bytes::from("hello")), trailers: Some(trailers) })
+            .unwrap();
+        let client = Client::builder().http2_only(true).build_http::<TrailerBody>();
+        let mut response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let body = response.body_mut();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("x-checksum").unwrap(), "abc");
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    fn slow_server() -> (String, Arc<AtomicUsize>) {
+        // Answers every request after a delay, recording the highest number of parallel requests
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+        let result = max_active.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let active = active.clone();
+                let max_active = max_active.clone();
+                thread::spawn(move || {
+                    let mut buffer = [0u8; 4096];
+                    let _ = stream.read(&mut buffer);
+                    let current = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(current, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(200));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+                });
+            }
+        });
+        (address, result)
+    }
+
+    #[tokio::test]
+    async fn test_limiting_concurrent_requests() {
+        let (upstream, max_active) = slow_server();
+        let port = free_port();
+        let config = listening_config(&upstream, port).with_max_connections(Some(1));
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+        let (sender, receiver) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(Arc::new(config), async {
+            receiver.await.ok();
+        }).unwrap());
+
+        let client = Client::new();
+        let request = |client: &Client<_>| {
+            let request = hyper::Request::builder()
+                .uri(format!("http://127.0.0.1:{}/", port))
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty())
+                .unwrap();
+            client.request(request)
+        };
+        let (first, second) = futures::join!(request(&client), request(&client));
+        assert_eq!(first.unwrap().status(), 200);
+        assert_eq!(second.unwrap().status(), 200);
+        assert_eq!(max_active.load(Ordering::SeqCst), 1);
+
+        sender.send(()).unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+}
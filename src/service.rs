@@ -1,78 +1,610 @@
 use std::sync::Arc;
-use serde::{Serialize};
-use hyper::{Request, Response, Body, StatusCode};
-use hyper::header::HeaderValue;
-use cookie::Cookie;
-use crate::config::{ProxyConfig, ArcAuthProxyConfig};
-use crate::proxy::run_proxy;
-use crate::auth::{AuthConfig, Token};
-use crate::credentials::CredentialsStore;
-use std::time::{SystemTime, Duration};
+use std::net::IpAddr;
+use serde::{Serialize, Serializer};
+use serde::ser::SerializeStruct;
+use hyper::{Request, Response, Body, StatusCode, Uri, Method};
+use hyper::header::{HeaderValue, AUTHORIZATION, COOKIE, HOST, PROXY_AUTHORIZATION};
+use http::uri::Authority;
+use cookie::{Cookie, SameSite};
+use crate::config::{ProxyConfig, ArcAuthProxyConfig, DEFAULT_RETRY_AFTER};
+use crate::proxy::{run_routed_proxy, upstream_request, client_ip, uses_trailers, UpstreamReachable};
+use crate::limit::UserPermit;
+use crate::cors;
+use crate::auth::{AuthConfig, Token, VerificationError, token_signature, csrf_token, login_csrf_token, verify_login_csrf_token};
+use crate::credentials::{CredentialsStore, random_salt};
+use std::time::{Instant, SystemTime};
 use std::ops::Deref;
 use time::OffsetDateTime;
 use tinytemplate::TinyTemplate;
-use futures::stream::TryStreamExt;
-
-
-fn get_username<'a, B, T: AuthConfig<'a>>(request: &Request<B>, config: &'a T) -> Option<String>{
-    match request.headers().get("Cookie").map(HeaderValue::to_str) {
-        Some(Ok(cookies)) => {
-            let auth_cookie = cookies.split(";")
-                .map(Cookie::parse)
-                .filter(Result::is_ok)
-                .map(Result::unwrap)
-                .filter(|c| c.name() == "proxy_auth")
-                .map(|c| String::from(c.value()))
-                .next();
-
-            match auth_cookie {
-                Some(token) => {
-                    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-                    Token::verify(&token, config, now).ok()
-                }
-                None => None
+use futures::stream::{StreamExt, TryStreamExt};
+use hyper::body::HttpBody;
+use percent_encoding::percent_decode_str;
+
+
+const SESSION_DURATION: u64 = 24 * 60 * 60;
+const ADMIN_ROLE: &str = "admin";
+const CANARY_ROLE: &str = "canary";
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+const LOGIN_CSRF_COOKIE: &str = "proxy_login_csrf";
+const LOGIN_CSRF_DURATION: u64 = 60 * 60;
+// The login and logout forms only carry a few short fields
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+// Attached to the responses of logged in users, so the access log can name them
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub String);
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn get_cookie<B>(request: &Request<B>, name: &str) -> Option<String> {
+    // Clients may split cookies across several headers, values that aren't UTF-8 are skipped
+    request.headers().get_all("Cookie").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .map(|cookie| cookie.trim())
+        .filter_map(|cookie| Cookie::parse(cookie).ok())
+        .filter(|c| c.name() == name)
+        .map(|c| String::from(c.value()))
+        .next()
+}
+
+fn get_auth_cookie<B>(request: &Request<B>) -> Option<String> {
+    get_cookie(request, "proxy_auth")
+}
+
+fn get_token<'a, B, T: AuthConfig<'a>>(request: &Request<B>, config: &'a T) -> Option<(Token, bool)>{
+    // The flag is set for tokens within the grace period, those may only be used once renewed
+    let auth_cookie = get_auth_cookie(request)?;
+    match Token::decode(&auth_cookie, config, unix_now()) {
+        Ok(token) => Some((token, false)),
+        Err(VerificationError::SoftExpired(token)) => Some((token, true)),
+        Err(_) => None
+    }
+}
+
+fn get_basic_credentials<B>(request: &Request<B>) -> Option<(String, String)> {
+    let value = request.headers().get("Authorization")?.to_str().ok()?;
+    if !value.starts_with("Basic ") {
+        return None;
+    }
+
+    let decoded = String::from_utf8(base64::decode(value[6..].trim()).ok()?).ok()?;
+    let pos = decoded.find(':')?;
+    Some((decoded[..pos].to_string(), decoded[pos + 1..].to_string()))
+}
+
+async fn authenticate_basic<B>(request: &Request<B>, config: &ProxyConfig) -> Option<Token> {
+    if !config.basic_auth() {
+        return None;
+    }
+
+    let (username, password) = get_basic_credentials(request)?;
+    let name = if username.is_empty() { None } else { Some(username.as_str()) };
+    let can_login = config.can_login(name, &password);
+    let username = if username.is_empty() { username } else { config.normalize_username(&username) };
+    // Scripts sending credentials with every request are logged and slowed down like the login form
+    let client_ip = client_ip(request, config.proxy_options().trusted_proxy);
+    let request_id = request.headers().get(REQUEST_ID_HEADER).and_then(|value| value.to_str().ok());
+    record_login_attempt(config, &username, client_ip, request_id, can_login).await;
+    if can_login {
+        let roles = user_roles(config, &username);
+        Some(Token::new(unix_now() + SESSION_DURATION, username).with_roles(roles))
+    } else {
+        None
+    }
+}
+
+async fn record_login_attempt(
+    config: &ProxyConfig, username: &str, client_ip: Option<IpAddr>, request_id: Option<&str>, success: bool
+) {
+    let audit_username = if username.is_empty() { None } else { Some(username) };
+    config.audit_log().login_attempt(audit_username, client_ip, request_id, success);
+    // Keyed like the request rate, so a client's failures and requests are kept together.
+    // A username only adds to the delay of an address, so guessing from elsewhere cannot lock out
+    // its user, and unknown usernames are not recorded at all
+    let delay_keys: Vec<String> = match client_ip {
+        Some(ip) => std::iter::once(address_key(ip))
+            .chain(audit_username.filter(|name| config.credentials_for(Some(name)).is_some()).map(user_key))
+            .collect(),
+        None => Vec::new()
+    };
+    if success {
+        config.rate_limiter().succeed_login(&delay_keys);
+    } else {
+        // The delay only slows down guessing, the response is the same as without it
+        let delay = config.rate_limiter().fail_login(&delay_keys, Instant::now());
+        tokio::time::delay_for(delay).await;
+    }
+}
+
+// Addresses and usernames share the rate limiter, the prefixes keep them apart
+fn address_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username)
+}
+
+fn is_api_client<B>(request: &Request<B>) -> bool {
+    match request.headers().get("Accept").map(HeaderValue::to_str) {
+        Some(Ok(accept)) => !accept.contains("text/html"),
+        _ => true
+    }
+}
+
+fn is_tiddlywiki_saver<B>(request: &Request<B>) -> bool {
+    request.headers().get("X-Requested-With")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"TiddlyWiki"))
+}
+
+/// Marks requests whose user was authenticated with the Authorization header
+struct BasicAuthenticated;
+
+fn is_admin(config: &ProxyConfig, token: &Token) -> bool {
+    // Roles come from the current users list, a token keeps them until it expires
+    user_roles(config, token.username()).iter().any(|role| role == ADMIN_ROLE)
+}
+
+fn user_roles(config: &ProxyConfig, username: &str) -> Vec<String> {
+    let name = if username.is_empty() { None } else { Some(username) };
+    config.credentials_for(name)
+        .map(|credentials| credentials.roles().to_vec())
+        .unwrap_or_default()
+}
+
+fn new_session(config: &ProxyConfig, username: String) -> Token {
+    let now = unix_now();
+    let max_expiration = now + SESSION_DURATION;
+    let roles = user_roles(config, &username);
+    match config.idle_timeout() {
+        Some(idle_timeout) => Token::new(max_expiration.min(now + idle_timeout), username)
+            .with_max_expiration(max_expiration)
+            .with_roles(roles),
+        None => Token::new(max_expiration, username).with_roles(roles)
+    }
+}
+
+fn is_active_user(config: &ProxyConfig, username: &str) -> bool {
+    // Sessions of users removed from the users file or denied are not renewed
+    let name = if username.is_empty() { None } else { Some(username) };
+    config.credentials_for(name).is_some() && !config.is_denied(name)
+}
+
+fn is_denied_user(config: &ProxyConfig, username: &str) -> bool {
+    config.is_denied(if username.is_empty() { None } else { Some(username) })
+}
+
+fn refresh_session(config: &ProxyConfig, token: &Token) -> Option<Token> {
+    // Tokens within the grace period are served after they expire, but a session is never
+    // extended past its maximum expiration, otherwise returning in time would renew it forever
+    let now = unix_now();
+    let max_expiration = token.max_expiration();
+    if now >= max_expiration || !is_active_user(config, token.username()) {
+        return None;
+    }
+    let expiration = match config.idle_timeout() {
+        Some(idle_timeout) => max_expiration.min(now + idle_timeout),
+        None => max_expiration
+    };
+    // Tokens issued without the idle timeout last for the whole session, they are re-issued with
+    // an explicit maximum so that the idle timeout applies to them as well
+    let is_legacy = config.idle_timeout().is_some() && !token.has_max_expiration();
+    if expiration > token.expiration() || is_legacy {
+        Some(Token::new(expiration, token.username().to_string())
+            .with_max_expiration(max_expiration)
+            .with_roles(token.roles().to_vec()))
+    } else {
+        None
+    }
+}
+
+fn is_secure<B>(request: &Request<B>, config: &ProxyConfig) -> bool {
+    // The header can be forged by clients, so it is only used when set by a proxy in front
+    if !config.trust_forwarded_proto() {
+        return false;
+    }
+    request.headers().get("X-Forwarded-Proto")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+fn build_cookie(config: &ProxyConfig, value: String, expires: OffsetDateTime, secure: bool) -> String {
+    // Browsers only replace or remove a cookie set with the same attributes,
+    // so the session and the clearing cookies are both built here
+    let mut cookie = Cookie::build("proxy_auth", value)
+        .path(cookie_path(config))
+        .http_only(true)
+        .secure(secure)
+        .expires(expires)
+        .finish();
+    if let Some(domain) = config.cookie_domain() {
+        cookie.set_domain(domain.to_string());
+    }
+    cookie.to_string()
+}
+
+fn session_cookie(config: &Arc<ProxyConfig>, token: &Token, secure: bool) -> String {
+    let value = token.generate(&ArcAuthProxyConfig::new(config.clone()));
+    // The browser keeps the cookie through the grace period, so the session can still be renewed
+    let expires = token.expiration().saturating_add(config.grace_period());
+    build_cookie(config, value, OffsetDateTime::from_unix_timestamp(expires as i64), secure)
+}
+
+fn clear_cookie(config: &ProxyConfig, secure: bool) -> String {
+    build_cookie(config, String::new(), OffsetDateTime::unix_epoch(), secure)
+}
+
+fn login_csrf_cookie(config: &ProxyConfig, value: String, expiration: u64, secure: bool) -> String {
+    Cookie::build(LOGIN_CSRF_COOKIE, value)
+        .path(cookie_path(config))
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .expires(OffsetDateTime::from_unix_timestamp(expiration as i64))
+        .finish()
+        .to_string()
+}
+
+
+fn strip_base_path(uri: &Uri, base_path: &str) -> Option<Uri> {
+    if base_path.is_empty() {
+        return Some(uri.clone());
+    }
+
+    let path = match uri.path().strip_prefix(base_path)? {
+        "" => "/",
+        path if path.starts_with('/') => path,
+        _ => return None
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string()
+    };
+    path_and_query.parse().ok()
+}
+
+fn cookie_path(config: &ProxyConfig) -> &str {
+    match config.base_path() {
+        "" => "/",
+        base_path => base_path
+    }
+}
+
+fn root_url(config: &ProxyConfig) -> String {
+    format!("{}/", config.base_path())
+}
+
+fn login_url(config: &ProxyConfig) -> String {
+    format!("{}{}", config.base_path(), config.login_path())
+}
+
+fn login_action_url(config: &ProxyConfig) -> String {
+    format!("{}{}", config.base_path(), config.login_action().unwrap_or_else(|| config.login_path()))
+}
+
+fn is_login_action(config: &ProxyConfig, path: &str) -> bool {
+    config.login_action().is_some_and(|action| path == action || path.strip_suffix('/') == Some(action))
+}
+
+fn logout_url(config: &ProxyConfig) -> String {
+    format!("{}{}/logout", config.base_path(), config.proxy_options().auth_prefix())
+}
+
+fn is_logout_path(config: &ProxyConfig, path: &str) -> bool {
+    path.strip_prefix(config.proxy_options().auth_prefix())
+        .is_some_and(|path| path == "/logout" || path == "/logout/")
+}
+
+fn is_styles_path(config: &ProxyConfig, path: &str) -> bool {
+    path.strip_prefix(config.proxy_options().auth_prefix())
+        .and_then(internal_name)
+        .is_some_and(|name| name == "styles.css")
+}
+
+fn is_login_path(config: &ProxyConfig, path: &str) -> bool {
+    path == config.login_path() || path.strip_suffix('/') == Some(config.login_path())
+}
+
+fn redirect(config: &ProxyConfig, location: &str) -> http::response::Builder {
+    // Values with line breaks could inject headers, so they are never echoed
+    let location = match HeaderValue::from_str(location) {
+        Ok(value) if !location.contains(['\r', '\n']) => value,
+        _ => HeaderValue::from_static("/")
+    };
+    Response::builder()
+        .status(config.redirect_status())
+        .header("Location", location)
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header("Allow", allow)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn forward_proxy_rejected() -> Response<Body> {
+    // Clients configured to use the server as a forward proxy would otherwise get a confusing redirect
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header("Allow", "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS")
+        .header("Content-Type", "text/plain")
+        .body(Body::from("CONNECT is not supported, this server is a reverse proxy for a wiki, not a forward proxy"))
+        .unwrap()
+}
+
+pub fn https_redirect<B>(request: &Request<B>, https_port: u16) -> Response<Body> {
+    // The plain HTTP port of the request is replaced with the one TLS is served on
+    let host = request.headers().get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Authority>().ok());
+    let port = if https_port == 443 { String::new() } else { format!(":{}", https_port) };
+    let location = host.map(|host| format!(
+        "https://{}{}{}", host.host(), port, request.uri().path_and_query().map_or("/", |path| path.as_str())
+    ));
+    match location.and_then(|location| HeaderValue::from_str(&location).ok()) {
+        Some(location) => Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header("Location", location)
+            .body(Body::empty())
+            .unwrap(),
+        None => text_response(StatusCode::BAD_REQUEST, "The Host header is missing or invalid")
+    }
+}
+
+fn has_path_traversal(path: &str) -> bool {
+    // Segments are decoded first, so "%2E%2E" and "..%2F" are caught as well. Only whole
+    // ".." segments count, titles such as "Chapter..2" or "Wait..." are left alone
+    path.split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy())
+        .any(|segment| segment.split(['/', '\\']).any(|part| part == ".."))
+}
+
+fn is_canary<B>(request: &Request<B>, token: &Token, config: &ProxyConfig) -> bool {
+    token.roles().iter().any(|role| role == CANARY_ROLE)
+        || config.canary_header().is_some_and(|header| request.headers().contains_key(header))
+}
+
+fn echo_path(name: &str) -> Option<&str> {
+    // "echo/<path>" shows how "<path>" would be forwarded, plain "echo" stands for the root
+    match name.strip_prefix("echo")? {
+        "" => Some("/"),
+        path if path.starts_with('/') => Some(path),
+        _ => None
+    }
+}
+
+fn internal_name(path: &str) -> Option<&str> {
+    // Paths under "proxy:" belong to the proxy and are never forwarded to the wiki
+    path.strip_prefix("/proxy:")
+        .or_else(|| path.strip_prefix("/proxy%3A"))
+        .or_else(|| path.strip_prefix("/proxy%3a"))
+}
+
+pub const STYLES: &str = include_str!("../data/styles.css");
+const STYLES_MAX_AGE: u64 = 24 * 60 * 60;
+
+const fn fnv1a_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < data.len() {
+        hash ^= data[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+// Computed at compile time, so it changes only when the stylesheet does
+const STYLES_HASH: u64 = fnv1a_hash(STYLES.as_bytes());
+
+fn styles_etag() -> String {
+    format!("\"{:016x}\"", STYLES_HASH)
+}
+
+fn etag_matches<B>(request: &Request<B>, etag: &str) -> bool {
+    request.headers().get_all("If-None-Match").iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
+fn styles_response<B>(request: &Request<B>) -> Response<Body> {
+    let etag = styles_etag();
+    let response = Response::builder()
+        .header("Cache-Control", format!("public, max-age={}", STYLES_MAX_AGE))
+        .header("ETag", &etag);
+    if etag_matches(request, &etag) {
+        return response.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+    }
+    response
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/css")
+        .body(Body::from(STYLES))
+        .unwrap()
+}
+
+fn internal_route<B>(request: &Request<B>, config: &ProxyConfig) -> Option<Response<Body>> {
+    if is_styles_path(config, request.uri().path()) {
+        return Some(match *request.method() {
+            Method::GET | Method::HEAD => styles_response(request),
+            _ => method_not_allowed("GET, HEAD")
+        });
+    }
+    // Admin routes require a session and are handled after authentication, other names do not exist
+    internal_name(request.uri().path())
+        .filter(|name| !name.starts_with("admin/") && echo_path(name).is_none())
+        .map(|_| Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap())
+}
+
+fn request_id<B>(request: &Request<B>, config: &ProxyConfig) -> HeaderValue {
+    // Ids from a trusted proxy in front are kept, so its logs can be matched with the wiki's
+    let forwarded = request.headers().get(REQUEST_ID_HEADER)
+        .filter(|_| config.proxy_options().trusted_proxy)
+        .filter(|value| (1..=MAX_REQUEST_ID_LENGTH).contains(&value.len()))
+        .filter(|value| value.as_bytes().iter().all(|c| c.is_ascii_alphanumeric() || b"-_.:".contains(c)));
+    match forwarded {
+        Some(value) => value.clone(),
+        None => HeaderValue::from_str(&format!("{:016x}", rand::random::<u64>())).unwrap()
+    }
+}
+
+pub async fn handle(mut request: Request<Body>, config: Arc<ProxyConfig>) -> Response<Body> {
+    let request_id = request_id(&request, &config);
+    request.headers_mut().insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let uri_length = request.uri().path_and_query().map_or(0, |path| path.as_str().len());
+    let origin = cors::allowed_origin(&request, config.cors_origins());
+    let mut response = match origin {
+        _ if uri_length > config.max_uri_length() => {
+            // Scripts of an allowed origin can read the status and shorten their request
+            let mut response = Response::builder()
+                .status(StatusCode::URI_TOO_LONG)
+                .body(Body::empty())
+                .unwrap();
+            if let Some(origin) = origin {
+                cors::add_headers(&mut response, origin);
+            }
+            response
+        },
+        _ if request.method() == Method::CONNECT => forward_proxy_rejected(),
+        Some(origin) if cors::is_preflight(&request) => cors::preflight_response(&request, origin),
+        origin => {
+            let mut response = route(request, config.clone()).await;
+            if let Some(origin) = origin {
+                cors::add_headers(&mut response, origin);
             }
+            response
         }
-        _ => None
+    };
+
+    // Configured headers replace the ones with the same name sent by the wiki
+    for (name, value) in config.response_headers() {
+        response.headers_mut().insert(name, value.clone());
     }
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
 }
 
+fn select_upstream<'a>(request: &mut Request<Body>, config: &'a ProxyConfig, token: &Token) -> Option<(&'a str, &'a Uri)> {
+    // The routed prefix is removed from the request's path and returned with the wiki
+    let (prefix, remote_uri) = config.upstream_for(request.uri().path())?;
+    *request.uri_mut() = strip_base_path(request.uri(), prefix).unwrap();
+    // Only the main wiki has a canary, routed prefixes always go to their own server
+    match config.canary_uri() {
+        Some(canary_uri) if prefix.is_empty() && is_canary(request, token, config) => Some((prefix, canary_uri)),
+        _ => Some((prefix, remote_uri))
+    }
+}
 
-pub async fn handle(request: Request<Body>, config: Arc<ProxyConfig>) -> Response<Body> {
-    match get_username(&request, config.deref()) {
-        Some(username) => {
-            let path = request.uri().path();
-            if path == "/logout" || path == "/logout/" {
-                let clear_cookie = Cookie::build("proxy_auth", "")
-                    .path("/")
-                    .http_only(true)
-                    .expires(OffsetDateTime::unix_epoch())
-                    .finish();
+async fn route(mut request: Request<Body>, config: Arc<ProxyConfig>) -> Response<Body> {
+    match strip_base_path(request.uri(), config.base_path()) {
+        Some(uri) => *request.uri_mut() = uri,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
 
-                Response::builder()
-                    .status(StatusCode::SEE_OTHER)
-                    .header("Location", "/")
-                    .header("Set-Cookie", &clear_cookie.to_string())
-                    .body(Body::empty())
-                    .unwrap()
+    if config.reject_path_traversal() && has_path_traversal(request.uri().path()) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // A dedicated action may be an internal route, so it is matched before them
+    if is_login_action(&config, request.uri().path()) {
+        return match *request.method() {
+            Method::GET | Method::HEAD | Method::POST => run_login_page(request, config).await,
+            _ => method_not_allowed("GET, HEAD, POST")
+        };
+    }
+
+    if !config.private_assets() {
+        if let Some(response) = internal_route(&request, &config) {
+            return response;
+        }
+    }
+
+    // A renewed session replaces the token for this request, so the wiki sees its new expiration.
+    // Past its expiration a session is only accepted when it can be renewed
+    let (token, renewed) = match get_token(&request, config.deref()) {
+        Some((token, soft_expired)) => match refresh_session(&config, &token) {
+            Some(renewed) => (Some(renewed), true),
+            None if soft_expired => (None, false),
+            None => (Some(token), false)
+        },
+        None => (None, false)
+    };
+    // Suspending a user ends their sessions, not only their future logins
+    let token = match token.filter(|token| !is_denied_user(&config, token.username())) {
+        Some(token) => Some(token),
+        None => {
+            let token = authenticate_basic(&request, &config).await;
+            if token.is_some() {
+                request.headers_mut().remove("Authorization");
+                request.extensions_mut().insert(BasicAuthenticated);
+            }
+            token
+        }
+    };
+
+    match token {
+        Some(token) => {
+            let user = AuthenticatedUser(token.username().to_string());
+            // The logout response clears the cookie, a renewed one must not replace it
+            let renewal = if renewed && !is_logout_path(&config, request.uri().path()) {
+                Some(session_cookie(&config, &token, is_secure(&request, &config)))
             } else {
-                run_proxy(request, config.remote_uri(), &username).await
+                None
+            };
+            let mut response = run_authenticated(request, config, token).await;
+            if let Some(cookie) = renewal {
+                response.headers_mut().append("Set-Cookie", HeaderValue::from_str(&cookie).unwrap());
             }
+            response.extensions_mut().insert(user);
+            response
         },
         None => {
             match request.uri().path() {
-                "/" => run_login_page(request, config).await,
-                "/proxy:styles.css" => {
+                path if is_login_path(&config, path) => {
+                    if request.method() == Method::POST && config.login_action().is_some() {
+                        method_not_allowed("GET, HEAD")
+                    } else {
+                        run_login_page(request, config).await
+                    }
+                },
+                _ if is_tiddlywiki_saver(&request) => {
+                    // The saver shows the body of a failed request, but cannot follow a redirect to a form
                     Response::builder()
-                        .status(StatusCode::OK)
-                        .header("Content-Type", "text/css")
-                        .body(Body::from(include_str!("../data/styles.css")))
+                        .status(StatusCode::UNAUTHORIZED)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("The session has expired, reload the page and log in again"))
                         .unwrap()
                 }
-                _ => {
+                _ if config.basic_auth() && is_api_client(&request) => {
                     Response::builder()
-                        .status(StatusCode::SEE_OTHER)
-                        .header("Location", "/")
+                        .status(StatusCode::UNAUTHORIZED)
+                        .header("WWW-Authenticate", format!("Basic realm=\"{}\", charset=\"UTF-8\"", config.basic_auth_realm()))
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("Authentication required"))
+                        .unwrap()
+                }
+                _ => {
+                    redirect(&config, &login_url(&config))
                         .body(Body::empty())
                         .unwrap()
                 }
@@ -82,10 +614,333 @@ pub async fn handle(request: Request<Body>, config: Arc<ProxyConfig>) -> Respons
 }
 
 
+async fn run_authenticated(mut request: Request<Body>, config: Arc<ProxyConfig>, token: Token) -> Response<Body> {
+    let internal = internal_name(request.uri().path()).map(String::from);
+    let path = request.uri().path();
+    if let Some(response) = internal_route(&request, &config) {
+        response
+    } else if let Some(path) = internal.as_deref().and_then(echo_path) {
+        let path = path.to_string();
+        run_echo(request, &config, &token, &path)
+    } else if let Some(name) = internal {
+        run_admin(request, config, &token, name).await
+    } else if is_logout_path(&config, path) {
+        run_logout(request, config, &token).await
+    } else if config.login_path() != "/" && is_login_path(&config, path) {
+        redirect(&config, &root_url(&config))
+            .body(Body::empty())
+            .unwrap()
+    } else if !config.is_path_allowed(path) {
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::empty())
+            .unwrap()
+    } else if config.maintenance() && !is_read_only(request.method()) {
+        maintenance_page(&config, path)
+    } else {
+        // Users without a name are told apart by their address
+        let client_ip = client_ip(&request, config.proxy_options().trusted_proxy);
+        let limit_key = match (token.username(), client_ip) {
+            ("", Some(ip)) => Some(address_key(ip)),
+            ("", None) => None,
+            (username, _) => Some(user_key(username))
+        };
+        if let Some(Err(retry_after)) = limit_key.as_ref()
+                .map(|key| config.rate_limiter().check(key, Instant::now())) {
+            return Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header("Content-Type", "text/plain")
+                .header("Retry-After", retry_after)
+                .body(Body::from("Too many requests, slow down"))
+                .unwrap()
+        }
+        let permit = match limit_key.map(|key| config.user_limiter().acquire(&key)) {
+            Some(None) => {
+                return Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Too many concurrent requests"))
+                    .unwrap()
+            },
+            Some(permit) => permit,
+            None => None
+        };
+
+        let (prefix, remote_uri) = match select_upstream(&mut request, &config, &token) {
+            Some(upstream) => upstream,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+
+        let trailers = uses_trailers(request.headers());
+        let response = run_routed_proxy(request, remote_uri, prefix, &token, config.proxy_options()).await;
+        // A 502 relayed from the wiki or caused by its content still means the wiki was reached
+        if let Some(UpstreamReachable(reachable)) = response.extensions().get() {
+            config.set_upstream_available(remote_uri, *reachable);
+        }
+        match permit {
+            // Trailers cannot be passed through a wrapped body, so those exchanges give the permit
+            // back as soon as the headers arrive
+            Some(permit) if !trailers => release_after_body(response, permit),
+            _ => response
+        }
+    }
+}
+
+fn release_after_body(response: Response<Body>, permit: UserPermit) -> Response<Body> {
+    // The permit is dropped with the body, once it was sent or the client went away
+    if response.body().is_end_stream() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = Body::wrap_stream(body.inspect(move |_| {
+        let _permit = &permit;
+    }));
+    Response::from_parts(parts, body)
+}
+
+fn is_read_only(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+#[derive(Serialize)]
+struct MaintenanceContext<'a> {
+    styles_url: String,
+    offline_banner: Option<&'a str>,
+    inline_styles: Option<&'a str>
+}
+
+fn maintenance_page(config: &ProxyConfig, path: &str) -> Response<Body> {
+    let mut template = TinyTemplate::new();
+    template.add_template("maintenance", include_str!("../data/maintenance.html")).unwrap();
+    let page = template.render("maintenance", &MaintenanceContext{
+        styles_url: config.proxy_options().styles_url(),
+        offline_banner: config.upstream_for(path).and_then(|(_, uri)| config.offline_banner(uri)),
+        inline_styles: config.proxy_options().inline_styles
+    }).unwrap();
+    // Saving clients retry on their own, the header tells them when it is worth trying again
+    let retry_after = config.proxy_options().retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Content-Type", "text/html")
+        .header("Retry-After", retry_after)
+        .body(Body::from(page))
+        .unwrap()
+}
+
+#[derive(Serialize)]
+struct LogoutFormContext<'a> {
+    styles_url: String,
+    logout_url: String,
+    title: &'a str,
+    csrf_token: &'a str,
+    inline_styles: Option<&'a str>
+}
+
+fn form_field(body: &[u8], name: &str) -> Option<String> {
+    url::form_urlencoded::parse(body).into_owned()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+fn logout(config: &ProxyConfig, auth_cookie: Option<&str>, token: &Token, secure: bool) -> Response<Body> {
+    if let Some(signature) = auth_cookie.and_then(token_signature) {
+        config.revoke_token(signature, token.expiration());
+    }
+
+    redirect(config, &root_url(config))
+        .header("Set-Cookie", clear_cookie(config, secure))
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn run_logout(request: Request<Body>, config: Arc<ProxyConfig>, token: &Token) -> Response<Body> {
+    // A plain link could log the user out from another site, so GET only asks for
+    // a confirmation, DELETE cannot be sent cross-site without a CORS preflight
+    let auth_cookie = get_auth_cookie(&request);
+    let secure = is_secure(&request, &config);
+    let expected_csrf_token = auth_cookie.as_deref()
+        .map(|cookie| csrf_token(cookie, config.deref()))
+        .unwrap_or_default();
+    match *request.method() {
+        Method::DELETE => logout(&config, auth_cookie.as_deref(), token, secure),
+        Method::POST => {
+            let body = read_body(request.into_body()).await;
+            if form_field(&body, "csrf_token").unwrap_or_default() == expected_csrf_token {
+                logout(&config, auth_cookie.as_deref(), token, secure)
+            } else {
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Invalid logout request"))
+                    .unwrap()
+            }
+        },
+        _ => {
+            let mut template = TinyTemplate::new();
+            template.add_template("logout", include_str!("../data/logout.html")).unwrap();
+
+            let context = LogoutFormContext{
+                styles_url: config.proxy_options().styles_url(),
+                logout_url: logout_url(&config),
+                title: config.login_title().unwrap_or("Logout"),
+                csrf_token: &expected_csrf_token,
+                inline_styles: config.proxy_options().inline_styles
+            };
+
+            let page = template.render("logout", &context).unwrap();
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/html")
+                .header("Content-Length", page.len())
+                .body(if request.method() == Method::HEAD { Body::empty() } else { Body::from(page) })
+                .unwrap()
+        }
+    }
+}
+
+fn text_response(status: StatusCode, text: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(text))
+        .unwrap()
+}
+
+fn run_echo(mut request: Request<Body>, config: &ProxyConfig, token: &Token, path: &str) -> Response<Body> {
+    // Shows the request as it would be forwarded, without contacting the wiki
+    if !is_admin(config, token) {
+        return text_response(StatusCode::FORBIDDEN, "Administrator role required");
+    }
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string()
+    };
+    *request.uri_mut() = match path_and_query.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return text_response(StatusCode::BAD_REQUEST, "Invalid path")
+    };
+    let remote_uri = match select_upstream(&mut request, config, token) {
+        Some((_, remote_uri)) => remote_uri,
+        None => return text_response(StatusCode::NOT_FOUND, "No wiki server for this path")
+    };
+
+    let forwarded = upstream_request(&request, remote_uri, token, config.proxy_options());
+    // Credentials are never shown, the page may end up in a screenshot or a bug report
+    let headers: Vec<(&str, &str)> = forwarded.headers().iter()
+        .map(|(name, value)| match *name {
+            AUTHORIZATION | PROXY_AUTHORIZATION | COOKIE => (name.as_str(), "[redacted]"),
+            _ => (name.as_str(), value.to_str().unwrap_or("[binary]"))
+        })
+        .collect();
+    let echo = serde_json::json!({
+        "method": forwarded.method().as_str(),
+        "uri": forwarded.uri().to_string(),
+        "headers": headers
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(echo.to_string()))
+        .unwrap()
+}
+
+async fn run_admin(request: Request<Body>, config: Arc<ProxyConfig>, token: &Token, name: String) -> Response<Body> {
+    if !is_admin(&config, token) {
+        return text_response(StatusCode::FORBIDDEN, "Administrator role required");
+    }
+    match name.as_str() {
+        "admin/passwd" if request.method() == Method::POST => run_passwd(request, config).await,
+        "admin/passwd" => method_not_allowed("POST"),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+async fn run_passwd(request: Request<Body>, config: Arc<ProxyConfig>) -> Response<Body> {
+    // Sessions from cookies need the CSRF token, so other sites cannot submit the form
+    let expected_csrf_token = match request.extensions().get::<BasicAuthenticated>() {
+        Some(_) => None,
+        None => Some(get_auth_cookie(&request)
+            .map(|cookie| csrf_token(&cookie, config.deref()))
+            .unwrap_or_default())
+    };
+    let body = read_body(request.into_body()).await;
+    if let Some(expected) = expected_csrf_token {
+        if expected.is_empty() || form_field(&body, "csrf_token").unwrap_or_default() != expected {
+            return text_response(StatusCode::FORBIDDEN, "Invalid request");
+        }
+    }
+
+    let password = match form_field(&body, "password") {
+        Some(password) if !password.is_empty() => password,
+        _ => return text_response(StatusCode::BAD_REQUEST, "Password cannot be empty")
+    };
+    let username = form_field(&body, "username").unwrap_or_default();
+    let name = if username.is_empty() { None } else { Some(username.as_str()) };
+    if config.credentials_for(name).is_none() {
+        return text_response(StatusCode::NOT_FOUND, "Unknown user");
+    }
+    match config.change_password(name, &password) {
+        Ok(()) => text_response(StatusCode::OK, "Password changed"),
+        Err(error) => {
+            eprintln!("Cannot change the password: {}", error);
+            text_response(StatusCode::INTERNAL_SERVER_ERROR, "Cannot change the password")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LoginStatus {
+    Ok,
+    WrongCredentials,
+    RateLimited { retry_after: u64 }
+}
+
+impl LoginStatus {
+    fn status_code(self) -> StatusCode {
+        match self {
+            LoginStatus::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            _ => StatusCode::OK
+        }
+    }
+}
+
+impl Serialize for LoginStatus {
+    // Templates cannot compare strings, so every state is exposed as its own flag
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let retry_after = match self {
+            LoginStatus::RateLimited { retry_after } => Some(*retry_after),
+            _ => None
+        };
+        let mut state = serializer.serialize_struct("LoginStatus", 3)?;
+        state.serialize_field("wrong_credentials", &(*self == LoginStatus::WrongCredentials))?;
+        state.serialize_field("rate_limited", &retry_after.is_some())?;
+        state.serialize_field("retry_after", &retry_after)?;
+        state.end()
+    }
+}
+
 #[derive(Serialize)]
-struct LoginFormContext {
-    wrong_credentials: bool,
-    requires_username: bool
+struct LoginFormContext<'a> {
+    #[serde(flatten)]
+    status: LoginStatus,
+    csrf_token: &'a str,
+    requires_username: bool,
+    styles_url: String,
+    login_url: String,
+    title: &'a str,
+    heading: Option<&'a str>,
+    logo_url: Option<&'a str>,
+    message: Option<&'a str>,
+    offline_banner: Option<&'a str>,
+    inline_styles: Option<&'a str>
 }
 
 fn extract_form_fields(body: &[u8]) -> (Option<String>, Option<String>) {
@@ -109,6 +964,7 @@ async fn read_body(mut body: hyper::Body) -> Vec<u8> {
     let mut data = Vec::new();
     loop {
         match body.try_next().await {
+            Ok(Some(chunk)) if data.len() + chunk.len() > MAX_FORM_SIZE => return vec![],
             Ok(Some(chunk)) => data.extend_from_slice(&chunk),
             Ok(None) => return data,
             Err(_) => return vec![]
@@ -116,72 +972,154 @@ async fn read_body(mut body: hyper::Body) -> Vec<u8> {
     }
 }
 
+fn render_login_form(source: &str, context: &LoginFormContext) -> String {
+    // A broken template must not lock users out, so a plain form is served instead
+    let mut template = TinyTemplate::new();
+    let page = template.add_template("login", source)
+        .and_then(|_| template.render("login", context));
+    match page {
+        Ok(page) => page,
+        Err(error) => {
+            eprintln!("Cannot render the login page: {}", error);
+            fallback_login_page(context)
+        }
+    }
+}
+
+fn fallback_login_page(context: &LoginFormContext) -> String {
+    let mut login_url = String::new();
+    tinytemplate::escape(&context.login_url, &mut login_url);
+    let mut csrf_token = String::new();
+    tinytemplate::escape(context.csrf_token, &mut csrf_token);
+    let username = if context.requires_username {
+        "<input type=\"text\" name=\"username\" placeholder=\"Username\" />"
+    } else {
+        ""
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"UTF-8\"><title>Login</title></head><body>\
+         <form method=\"POST\" action=\"{}\">{}\
+         <input type=\"password\" name=\"password\" placeholder=\"Password\" />\
+         <input type=\"hidden\" name=\"csrf_token\" value=\"{}\" />\
+         <input type=\"submit\" value=\"Login\"></form></body></html>",
+        login_url, username, csrf_token
+    )
+}
+
 async fn run_login_page(request: Request<Body>, config: Arc<ProxyConfig>) -> Response<Body> {
-    let wrong_password = if request.method() == "POST" {
+    let is_head = request.method() == Method::HEAD;
+    let client_ip = client_ip(&request, config.proxy_options().trusted_proxy);
+    let secure = is_secure(&request, &config);
+    let csrf_cookie = get_cookie(&request, LOGIN_CSRF_COOKIE);
+    let request_id = request.headers().get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let status = if request.method() == "POST" {
         let body = read_body(request.into_body()).await;
+        // The form carries the same signed token as the cookie, a cross-site form cannot read either
+        let csrf_valid = match (csrf_cookie, form_field(&body, "csrf_token")) {
+            (Some(cookie), Some(field)) => cookie == field
+                && verify_login_csrf_token(&cookie, config.deref(), unix_now()),
+            _ => false
+        };
+        if !csrf_valid {
+            return text_response(
+                StatusCode::BAD_REQUEST, "The login form has expired, reload the page and try again"
+            );
+        }
         let fields = extract_form_fields(&body);
-        match fields{
-            (None, None) => false,
-            (_, None) => true,
-            (username, Some(password)) => {
+        // Login attempts are anonymous, so they count against the address like other such requests
+        let rate_limit = client_ip
+            .map(|ip| config.rate_limiter().check(&address_key(ip), Instant::now()))
+            .unwrap_or(Ok(()));
+        match (fields, rate_limit) {
+            ((None, None), _) => LoginStatus::Ok,
+            ((_, None), _) => LoginStatus::WrongCredentials,
+            (_, Err(retry_after)) => LoginStatus::RateLimited { retry_after },
+            ((username, Some(password)), Ok(())) => {
                 let (can_login, username) = match username {
                     Some(username) => (
                         config.can_login(Some(&username), &password),
-                        String::from(username)
+                        config.normalize_username(&username)
                     ),
                     None => (config.can_login(None, &password), String::new())
                 };
+                record_login_attempt(&config, &username, client_ip, request_id.as_deref(), can_login).await;
                 if can_login {
-                    let expires = SystemTime::now() + Duration::new(24 * 60 * 60, 0);
-                    let token = Token::new(
-                        expires.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
-                        username
-                    );
-
-                    let arc_config = token.generate(&ArcAuthProxyConfig::new(config.clone()));
-                    let auth_cookie = Cookie::build("proxy_auth", &arc_config)
-                        .path("/")
-                        .http_only(true)
-                        .expires(OffsetDateTime::from(expires))
-                        .finish();
-
-                    return Response::builder()
-                        .status(StatusCode::SEE_OTHER)
-                        .header("Location", "/")
-                        .header("Set-Cookie", &auth_cookie.to_string())
+                    let token = new_session(&config, username);
+                    return redirect(&config, &root_url(&config))
+                        .header("Set-Cookie", session_cookie(&config, &token, secure))
                         .body(Body::empty())
                         .unwrap()
                 } else {
-                    true
+                    LoginStatus::WrongCredentials
                 }
             }
         }
     } else {
-        false
+        LoginStatus::Ok
     };
+    login_form_response(&config, status, is_head, secure)
+}
 
-    let mut template = TinyTemplate::new();
-    template.add_template("login", include_str!("../data/login.html")).unwrap();
-
+fn login_form_response(config: &ProxyConfig, status: LoginStatus, is_head: bool, secure: bool) -> Response<Body> {
+    let csrf_expiration = unix_now() + LOGIN_CSRF_DURATION;
+    let csrf_token = login_csrf_token(&random_salt(16), csrf_expiration, config);
     let context = LoginFormContext{
-        wrong_credentials: wrong_password,
-        requires_username: config.requires_username()
+        status,
+        csrf_token: &csrf_token,
+        requires_username: config.requires_username(),
+        styles_url: config.proxy_options().styles_url(),
+        login_url: login_action_url(config),
+        title: config.login_title().unwrap_or("Login"),
+        heading: config.login_title(),
+        logo_url: config.login_logo_url(),
+        message: config.login_message(),
+        offline_banner: config.offline_banner(config.remote_uri()),
+        // The stylesheet route requires a session, so the page cannot link to it
+        inline_styles: config.proxy_options().inline_styles
     };
 
-    Response::builder()
-        .status(StatusCode::OK)
+    let page = render_login_form(include_str!("../data/login.html"), &context);
+    let mut response = Response::builder()
+        .status(status.status_code())
         .header("Content-Type", "text/html")
-        .body(Body::from(template.render("login", &context).unwrap()))
+        .header("Content-Length", page.len())
+        .header("Set-Cookie", login_csrf_cookie(config, csrf_token, csrf_expiration, secure));
+    if let LoginStatus::RateLimited { retry_after } = status {
+        response = response.header("Retry-After", retry_after);
+    }
+    response
+        .body(if is_head { Body::empty() } else { Body::from(page) })
         .unwrap()
 }
 
 
 #[cfg(test)]
 mod tests {
+    pub fn make_config(wiki_url: &str) -> crate::config::ProxyConfig {
+        // A single user "user" with the password "password"
+        crate::config::ProxyConfig::from_values(
+            wiki_url,
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap()
+    }
+
+    pub fn login_form(
+        request: http::request::Builder, config: &crate::config::ProxyConfig, fields: &str
+    ) -> hyper::Request<hyper::Body> {
+        let token = super::login_csrf_token("test", super::unix_now() + 60, config);
+        request
+            .header("Cookie", format!("{}={}", super::LOGIN_CSRF_COOKIE, token))
+            .body(hyper::Body::from(format!("{}&csrf_token={}", fields, token))).unwrap()
+    }
+
     mod test_is_authenticated {
         use std::time::SystemTime;
         use hyper::Request;
-        use super::super::get_username;
+        use super::super::get_token;
         use crate::auth::Token;
         use crate::auth::tests::MockConfig;
 
@@ -189,7 +1127,7 @@ mod tests {
         fn test_auth_no_cookies() {
             let request = Request::builder().body(()).unwrap();
             let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
-            assert_eq!(get_username(&request, &config), None);
+            assert_eq!(get_token(&request, &config), None);
         }
 
         #[test]
@@ -200,7 +1138,7 @@ mod tests {
                 .unwrap();
 
             let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
-            assert_eq!(get_username(&request, &config), None);
+            assert_eq!(get_token(&request, &config), None);
         }
 
         #[test]
@@ -211,7 +1149,7 @@ mod tests {
                 .unwrap();
 
             let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
-            assert_eq!(get_username(&request, &config), None);
+            assert_eq!(get_token(&request, &config), None);
         }
 
         #[test]
@@ -222,7 +1160,7 @@ mod tests {
                 .unwrap();
 
             let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
-            assert_eq!(get_username(&request, &config), None);
+            assert_eq!(get_token(&request, &config), None);
         }
 
         #[test]
@@ -237,224 +1175,2463 @@ mod tests {
                 ))
                 .body(())
                 .unwrap();
-            assert_eq!(get_username(&request, &config), None);
+            assert_eq!(get_token(&request, &config), None);
         }
 
         #[test]
         fn test_auth_valid_token() {
             let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-            let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
+            let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
+
+            let request = Request::builder()
+                .header("Cookie", format!(
+                    "cookie1=2; proxy_auth={}; cookie2=3",
+                    Token::new(now + 100, String::from("user")).generate(&config)
+                ))
+                .body(())
+                .unwrap();
+            assert_eq!(
+                get_token(&request, &config).map(|(token, _)| token.username().to_string()),
+                Some(String::from("user"))
+            );
+        }
+
+        #[test]
+        fn test_auth_multiple_cookie_headers() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
+
+            let request = Request::builder()
+                .header("Cookie", "cookie1=2; cookie2=3")
+                .header("Cookie", format!(
+                    "proxy_auth={}; cookie3=4",
+                    Token::new(now + 100, String::from("user")).generate(&config)
+                ))
+                .body(())
+                .unwrap();
+            assert_eq!(
+                get_token(&request, &config).map(|(token, _)| token.username().to_string()),
+                Some(String::from("user"))
+            );
+        }
+
+        #[test]
+        fn test_auth_skips_non_utf8_cookie_header() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let config = MockConfig::new(*b"00112233445566778899AABBCCDDEEFF");
+
+            let request = Request::builder()
+                .header("Cookie", &b"cookie1=\xff"[..])
+                .header("Cookie", format!(
+                    "proxy_auth={}",
+                    Token::new(now + 100, String::from("user")).generate(&config)
+                ))
+                .body(())
+                .unwrap();
+            assert!(get_token(&request, &config).is_some());
+        }
+    }
+
+    mod test_navigation {
+        use std::sync::Arc;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body, StatusCode};
+        use crate::config::ProxyConfig;
+        use crate::auth::{Token, csrf_token};
+        use super::super::{handle, AuthenticatedUser};
+        use std::time::SystemTime;
+        use futures::stream::StreamExt;
+        use cookie::Cookie;
+        use std::net::SocketAddr;
+        use std::time::Duration;
+        use crate::audit::AuditLog;
+        use rstest::rstest;
+
+        #[tokio::test]
+        async fn test_redirecting_unauthenticated_to_login_page(){
+            let mock_server = MockServer::start();
+            let config = ProxyConfig::from_values(
+                &format!("{}", mock_server.address()),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/hello")
+                .create_on(&mock_server);
+
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/");
+            assert!(resp.extensions().get::<AuthenticatedUser>().is_none());
+            assert_eq!(mock.times_called(), 0);
+        }
+
+        #[tokio::test]
+        async fn test_unauthenticated_tiddlywiki_saver(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers/Note".parse::<Uri>().unwrap())
+                .method("PUT")
+                .header("X-Requested-With", "TiddlyWiki")
+                .header("Accept", "text/html")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 401);
+            assert!(resp.headers().get("Location").is_none());
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(body, "The session has expired, reload the page and log in again");
+        }
+
+        #[tokio::test]
+        async fn test_running_proxy_when_authenticated(){
+            let mock_server = MockServer::start();
+            let config = ProxyConfig::from_values(
+                &format!("{}", mock_server.address()),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/hello")
+                .expect_header("X-Auth-Username", "user")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.extensions().get::<AuthenticatedUser>().unwrap().0, "user");
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(body, "remote content");
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_logging_out(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let csrf_token = csrf_token(&token, &config);
+            let config = Arc::new(config);
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("action=\"/logout\""));
+            assert!(body.contains(&format!("value=\"{}\"", csrf_token)));
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("POST")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::from("csrf_token=invalid")).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 403);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("POST")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::from(format!("csrf_token={}", csrf_token))).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/");
+            assert_eq!(
+                resp.headers().get("Set-Cookie").unwrap(),
+                "proxy_auth=; HttpOnly; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_cookie_domain(){
+            let config = Arc::new(super::make_config("localhost")
+                .with_cookie_domain(Some("example.com".to_string())));
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap()).unwrap();
+            assert_eq!(cookie.domain(), Some("example.com"));
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("DELETE")
+                .header("Cookie", format!("proxy_auth={}", cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(
+                resp.headers().get("Set-Cookie").unwrap(),
+                "proxy_auth=; HttpOnly; Path=/; Domain=example.com; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
+            );
+        }
+
+        #[tokio::test]
+        async fn test_clear_cookie_attributes(){
+            let config = Arc::new(super::make_config("localhost")
+                .with_base_path("/wiki".to_string())
+                .with_cookie_domain(Some("example.com".to_string()))
+                .with_trust_forwarded_proto(true));
+
+            let request = super::login_form(Request::builder()
+                .uri("/wiki/".parse::<Uri>().unwrap())
+                .method("POST")
+                .header("X-Forwarded-Proto", "https"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            let set_cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string()).unwrap();
+
+            let request = Request::builder()
+                .uri("/wiki/logout".parse::<Uri>().unwrap())
+                .method("DELETE")
+                .header("X-Forwarded-Proto", "https")
+                .header("Cookie", format!("proxy_auth={}", set_cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            let clear_cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string()).unwrap();
+
+            assert_eq!(clear_cookie.value(), "");
+            assert_eq!(clear_cookie.path(), Some("/wiki"));
+            assert_eq!(clear_cookie.domain(), set_cookie.domain());
+            assert_eq!(clear_cookie.path(), set_cookie.path());
+            assert_eq!(clear_cookie.secure(), set_cookie.secure());
+            assert_eq!(clear_cookie.http_only(), set_cookie.http_only());
+            assert_eq!(clear_cookie.same_site(), set_cookie.same_site());
+        }
+
+        #[tokio::test]
+        async fn test_logging_out_with_found_redirect(){
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap().with_redirect_status(StatusCode::FOUND);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("DELETE")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 302);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/");
+        }
+
+        #[tokio::test]
+        async fn test_replaying_token_after_logout(){
+            let config = Arc::new(ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap());
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
+            let other_token = Token::new(now + 200, String::from("user")).generate(config.as_ref());
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("DELETE")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+
+            let request = Request::builder()
+                .uri("/logout".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+
+            assert!(super::super::get_token(&Request::builder()
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(()).unwrap(), config.as_ref()).is_none());
+            assert!(super::super::get_token(&Request::builder()
+                .header("Cookie", format!("proxy_auth={}", other_token))
+                .body(()).unwrap(), config.as_ref()).is_some());
+        }
+
+        #[tokio::test]
+        async fn test_logging_in_wrong_password(){
+            let config = super::make_config("localhost");
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=wrong_password");
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 200);
+            assert!(!resp.headers().get_all("Set-Cookie").iter()
+                .any(|cookie| cookie.to_str().unwrap().starts_with("proxy_auth=")));
+        }
+
+        #[tokio::test]
+        async fn test_logging_missing_username(){
+            let config = super::make_config("localhost");
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "password=password");
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 200);
+            assert!(!resp.headers().get_all("Set-Cookie").iter()
+                .any(|cookie| cookie.to_str().unwrap().starts_with("proxy_auth=")));
+        }
+
+
+        #[tokio::test]
+        async fn test_logging_in(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/")
+                .expect_header("X-Auth-Username", "user")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()));
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+
+            let config = Arc::new(config);
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/");
+            assert_eq!(mock.times_called(), 0);
+
+            let cookie = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap();
+            let token = String::from(Cookie::parse(cookie).unwrap().value());
+
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[rstest(trust_forwarded_proto, forwarded_proto, secure,
+            case(true, Some("https"), true),
+            case(true, Some("HTTPS, http"), true),
+            case(true, Some("http"), false),
+            case(true, None, false),
+            case(false, Some("https"), false),
+        )]
+        #[tokio::test]
+        async fn test_secure_cookie(trust_forwarded_proto: bool, forwarded_proto: Option<&str>, secure: bool){
+            let config = Arc::new(super::make_config("localhost")
+                .with_trust_forwarded_proto(trust_forwarded_proto));
+
+            let mut request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST");
+            if let Some(proto) = forwarded_proto {
+                request = request.header("X-Forwarded-Proto", proto);
+            }
+            let request = super::login_form(request, &config, "username=user&password=password");
+
+            let resp = handle(request, config).await;
+            assert_eq!(resp.status(), 303);
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap()).unwrap();
+            assert_eq!(cookie.secure() == Some(true), secure);
+        }
+
+        #[tokio::test]
+        async fn test_logging_in_with_roles(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/")
+                .expect_header("X-Auth-Username", "user")
+                .expect_header("X-Auth-Roles", "editor,admin")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = Arc::new(ProxyConfig::from_values(
+                &format!("{}", mock_server.address()),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8:editor,admin",
+                None, None
+            ).unwrap());
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            let cookie = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap();
+            let token = String::from(Cookie::parse(cookie).unwrap().value());
+
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .header("X-Auth-Roles", "superuser")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_audit_log(){
+            let path = std::env::temp_dir().join(format!("tiddlyproxy-audit-{}.log", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let config = Arc::new(super::make_config("localhost")
+                .with_audit_log(AuditLog::open(&path).unwrap()));
+
+            for body in &["username=user&password=wrong", "username=user&password=password"] {
+                let mut request = super::login_form(Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .method("POST"), &config, body);
+                request.extensions_mut().insert("127.0.0.1:50000".parse::<SocketAddr>().unwrap());
+                handle(request, config.clone()).await;
+            }
+
+            let mut lines = Vec::new();
+            for _ in 0..50 {
+                lines = std::fs::read_to_string(&path).unwrap_or_default()
+                    .lines().map(String::from).collect();
+                if lines.len() == 2 {
+                    break;
+                }
+                tokio::time::delay_for(Duration::from_millis(20)).await;
+            }
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(lines.len(), 2);
+            assert!(lines[0].contains(" login failure user=\"user\" client=127.0.0.1 request="));
+            assert!(lines[1].contains(" login success user=\"user\" client=127.0.0.1 request="));
+            assert!(!lines.iter().any(|line| line.contains("password")));
+        }
+
+        #[tokio::test]
+        async fn test_login_rate_limited(){
+            let config = Arc::new(super::make_config("localhost").with_user_rate(Some(1.0 / 60.0), Some(1)));
+            let request = |address: &str| {
+                let mut request = super::login_form(Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .method("POST"), &config, "username=user&password=wrong");
+                request.extensions_mut().insert(address.parse::<SocketAddr>().unwrap());
+                request
+            };
+
+            assert_eq!(handle(request("127.0.0.1:50000"), config.clone()).await.status(), 200);
+            let resp = handle(request("127.0.0.1:50000"), config.clone()).await;
+            assert_eq!(resp.status(), 429);
+            let retry_after: u64 = resp.headers().get("Retry-After").unwrap().to_str().unwrap().parse().unwrap();
+            assert!(retry_after > 0 && retry_after <= 60);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("Too many failed attempts"));
+
+            assert_eq!(handle(request("127.0.0.2:50000"), config.clone()).await.status(), 200);
+        }
+
+        #[tokio::test]
+        async fn test_logging_in_no_username(){
+            let config = ProxyConfig::from_values(
+                &format!("localhost"),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                None, None
+            ).unwrap();
+
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "password=password");
+
+            let config = Arc::new(config);
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/");
+            assert!(resp.headers().get("Set-Cookie").is_some());
+        }
+
+        #[tokio::test]
+        async fn test_conditional_save(){
+            let mock_server = MockServer::start();
+            let get_mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/recipes/default/tiddlers/Foo")
+                .return_status(200)
+                .return_header("ETag", "\"default/Foo/41:\"")
+                .return_body("{\"title\":\"Foo\"}")
+                .create_on(&mock_server);
+            let put_mock = Mock::new()
+                .expect_method(httpmock::Method::PUT)
+                .expect_path("/recipes/default/tiddlers/Foo")
+                .expect_header("If-Match", "\"default/Foo/41:\"")
+                .expect_header("X-Requested-With", "TiddlyWiki")
+                .expect_body("{\"title\":\"Foo\",\"text\":\"bar\"}")
+                .return_status(204)
+                .return_header("ETag", "\"default/Foo/42:\"")
+                .create_on(&mock_server);
+
+            let config = ProxyConfig::from_values(
+                &format!("{}", mock_server.address()),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers/Foo".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let etag = resp.headers().get("ETag").unwrap().clone();
+            assert_eq!(etag, "\"default/Foo/41:\"");
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers/Foo".parse::<Uri>().unwrap())
+                .method("PUT")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .header("If-Match", etag)
+                .header("X-Requested-With", "TiddlyWiki")
+                .header("Content-Type", "application/json")
+                .body(Body::from("{\"title\":\"Foo\",\"text\":\"bar\"}")).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 204);
+            assert_eq!(resp.headers().get("ETag").unwrap(), "\"default/Foo/42:\"");
+            assert_eq!(get_mock.times_called(), 1);
+            assert_eq!(put_mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_base_path(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/hello")
+                .expect_query_param("a", "1")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_base_path("/wiki".to_string());
+            let config = Arc::new(config);
+
+            let request = super::login_form(Request::builder()
+                .uri("/wiki/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/");
+
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap()).unwrap();
+            assert_eq!(cookie.path(), Some("/wiki"));
+
+            let request = Request::builder()
+                .uri("/wiki/hello?a=1".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 404);
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_cross_origin_requests(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/recipes/default/tiddlers.json")
+                .return_body("[]")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_cors_origins(vec!["https://example.com".to_string()]);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers.json".parse::<Uri>().unwrap())
+                .method("OPTIONS")
+                .header("Origin", "https://example.com")
+                .header("Access-Control-Request-Method", "GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 204);
+            assert_eq!(resp.headers().get("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+            assert_eq!(mock.times_called(), 0);
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers.json".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Origin", "https://example.com")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("Access-Control-Allow-Origin").unwrap(), "https://example.com");
+            assert_eq!(resp.headers().get("Access-Control-Allow-Credentials").unwrap(), "true");
+            assert_eq!(mock.times_called(), 1);
+
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers.json".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Origin", "https://example.org")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert!(resp.headers().get("Access-Control-Allow-Origin").is_none());
+        }
+
+        #[tokio::test]
+        async fn test_custom_login_path(){
+            let config = Arc::new(super::make_config("localhost")
+                .with_base_path("/wiki".to_string())
+                .with_login_path("/login".to_string()));
+
+            let request = Request::builder()
+                .uri("/wiki/".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/login");
+
+            let request = Request::builder()
+                .uri("/wiki/login".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("action=\"/wiki/login\""));
+            assert!(body.contains("href=\"/wiki/login/proxy:styles.css\""));
+
+            // The stylesheet and the logout page move under the login path
+            let request = Request::builder()
+                .uri("/wiki/login/proxy:styles.css".parse::<Uri>().unwrap())
+                .body(Body::empty()).unwrap();
+            assert_eq!(handle(request, config.clone()).await.status(), 200);
+            let request = Request::builder()
+                .uri("/wiki/proxy:styles.css".parse::<Uri>().unwrap())
+                .body(Body::empty()).unwrap();
+            assert_eq!(handle(request, config.clone()).await.status(), 404);
+
+            let request = super::login_form(Request::builder()
+                .uri("/wiki/login".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/");
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap()).unwrap();
+
+            let request = Request::builder()
+                .uri("/wiki/login".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/");
+
+            let request = Request::builder()
+                .uri("/wiki/login/logout".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", cookie.value()))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("action=\"/wiki/login/logout\""));
+            assert!(body.contains("href=\"/wiki/login/proxy:styles.css\""));
+        }
+
+        #[tokio::test]
+        async fn test_login_action(){
+            let config = Arc::new(super::make_config("localhost")
+                .with_base_path("/wiki".to_string())
+                .with_login_action(Some("/proxy:login".to_string())));
+
+            let request = Request::builder()
+                .uri("/wiki/".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("action=\"/wiki/proxy:login\""));
+
+            let request = super::login_form(Request::builder()
+                .uri("/wiki/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 405);
+            assert_eq!(resp.headers().get("Allow").unwrap(), "GET, HEAD");
+
+            let request = super::login_form(Request::builder()
+                .uri("/wiki/proxy:login".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=user&password=password");
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/");
+            assert!(resp.headers().get("Set-Cookie").is_some());
+
+            let request = Request::builder()
+                .uri("/wiki/proxy:login".parse::<Uri>().unwrap())
+                .method("DELETE")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 405);
+        }
+
+        #[tokio::test]
+        async fn test_static_response_headers(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/")
+                .return_header("X-Frame-Options", "SAMEORIGIN")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address())).with_response_headers(vec![
+                ("x-frame-options".parse().unwrap(), "DENY".parse().unwrap()),
+                ("strict-transport-security".parse().unwrap(), "max-age=31536000".parse().unwrap())
+            ]);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("X-Frame-Options").unwrap(), "DENY");
+            assert_eq!(resp.headers().get("Strict-Transport-Security").unwrap(), "max-age=31536000");
+
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+            assert_eq!(resp.headers().get_all("X-Frame-Options").iter().collect::<Vec<_>>(), vec!["DENY"]);
+            assert_eq!(resp.headers().get("Strict-Transport-Security").unwrap(), "max-age=31536000");
+        }
+
+        #[tokio::test]
+        async fn test_concurrent_requests_per_user(){
+            // The upstream answers after a delay, so the first request is still running
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    std::thread::spawn(move || {
+                        use std::io::{Read, Write};
+                        let mut buffer = [0u8; 4096];
+                        let _ = stream.read(&mut buffer);
+                        std::thread::sleep(Duration::from_millis(300));
+                        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+                    });
+                }
+            });
+
+            let config = super::make_config(&address).with_max_user_requests(Some(1));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let other_token = Token::new(now + 100, String::from("other")).generate(&config);
+            let config = Arc::new(config);
+            let request = |token: &str| Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let first = tokio::spawn(handle(request(&token), config.clone()));
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            let (second, other) = futures::join!(
+                handle(request(&token), config.clone()),
+                handle(request(&other_token), config.clone())
+            );
+            assert_eq!(second.status(), 429);
+            assert_eq!(other.status(), 200);
+            assert_eq!(first.await.unwrap().status(), 200);
+
+            let resp = handle(request(&token), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        #[tokio::test]
+        async fn test_concurrent_request_held_until_body_is_read(){
+            let mock_server = MockServer::start();
+            Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_max_user_requests(Some(1));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+            let request = || Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let first = handle(request(), config.clone()).await;
+            assert_eq!(first.status(), 200);
+            assert_eq!(handle(request(), config.clone()).await.status(), 429);
+
+            let body = String::from_utf8(first.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(body, "remote content");
+            assert_eq!(handle(request(), config.clone()).await.status(), 200);
+        }
+
+        #[tokio::test]
+        async fn test_request_rate_per_user(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_user_rate(Some(1.0 / 60.0), Some(2));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let other_token = Token::new(now + 100, String::from("other")).generate(&config);
+            let config = Arc::new(config);
+            let request = |token: &str| Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            for _ in 0..2 {
+                assert_eq!(handle(request(&token), config.clone()).await.status(), 200);
+            }
+            let resp = handle(request(&token), config.clone()).await;
+            assert_eq!(resp.status(), 429);
+            let retry_after: u64 = resp.headers().get("Retry-After").unwrap().to_str().unwrap().parse().unwrap();
+            assert!(retry_after > 0 && retry_after <= 60);
+
+            let resp = handle(request(&other_token), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 3);
+        }
+
+        #[rstest(trusted, expected,
+            case(false, 429),
+            case(true, 200),
+        )]
+        #[tokio::test]
+        async fn test_request_rate_per_forwarded_address(trusted: bool, expected: u16){
+            // Without a username users are told apart by address, which the proxy in front reports
+            let mock_server = MockServer::start();
+            Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = ProxyConfig::from_values(
+                &format!("{}", mock_server.address()),
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                None, None
+            ).unwrap().with_user_rate(Some(1.0 / 60.0), Some(1)).with_trusted_proxy(trusted);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::new()).generate(&config);
+            let config = Arc::new(config);
+            let request = |forwarded_for: &str| {
+                let mut request = Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .header("Cookie", format!("proxy_auth={}", token))
+                    .header("X-Forwarded-For", forwarded_for)
+                    .body(Body::empty()).unwrap();
+                request.extensions_mut().insert("10.0.0.2:50000".parse::<SocketAddr>().unwrap());
+                request
+            };
+
+            assert_eq!(handle(request("203.0.113.7"), config.clone()).await.status(), 200);
+            assert_eq!(handle(request("198.51.100.1"), config.clone()).await.status(), expected);
+        }
+
+        #[tokio::test]
+        async fn test_allowed_paths(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_allowed_paths(Some(vec!["/".to_string(), "/recipes/*".to_string()]));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+            let request = |path: &str| Request::builder()
+                .uri(path.parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request("/recipes/default/tiddlers.json"), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+
+            let resp = handle(request("/status"), config.clone()).await;
+            assert_eq!(resp.status(), 403);
+            assert_eq!(mock.times_called(), 1);
+
+            let resp = handle(request("/logout"), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let resp = handle(request("/proxy:styles.css"), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_maintenance_mode(){
+            let mock_server = MockServer::start();
+            let get_mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/recipes/default/tiddlers/Note")
+                .return_body("{}")
+                .create_on(&mock_server);
+            let put_mock = Mock::new()
+                .expect_method(httpmock::Method::PUT)
+                .expect_path("/recipes/default/tiddlers/Note")
+                .return_status(204)
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+            let request = |method: &str| Request::builder()
+                .uri("/recipes/default/tiddlers/Note".parse::<Uri>().unwrap())
+                .method(method)
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            config.set_maintenance(true);
+            let resp = handle(request("GET"), config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(get_mock.times_called(), 1);
+
+            let resp = handle(request("PUT"), config.clone()).await;
+            assert_eq!(resp.status(), 503);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/html");
+            assert_eq!(resp.headers().get("Retry-After").unwrap(), "30");
+            assert_eq!(put_mock.times_called(), 0);
+
+            config.set_maintenance(false);
+            let resp = handle(request("PUT"), config.clone()).await;
+            assert_eq!(resp.status(), 204);
+            assert_eq!(put_mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_uri_too_long(){
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_max_uri_length(32)
+                .with_cors_origins(vec!["https://app.example.com".to_string()])
+                .with_response_headers(vec![("x-frame-options".parse().unwrap(), "DENY".parse().unwrap())]);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let config = Arc::new(config);
+
+            let request = Request::builder()
+                .uri(format!("/{}?q={}", "a".repeat(20), "b".repeat(20)).parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 414);
+            assert_eq!(mock.times_called(), 0);
+
+            let request = Request::builder()
+                .uri(format!("/{}", "a".repeat(40)).parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Origin", "https://app.example.com")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 414);
+            assert_eq!(resp.headers().get("Access-Control-Allow-Origin").unwrap(), "https://app.example.com");
+            assert_eq!(resp.headers().get("X-Frame-Options").unwrap(), "DENY");
+            assert!(resp.headers().contains_key("X-Request-Id"));
+
+            let request = Request::builder()
+                .uri(format!("/{}", "a".repeat(31)).parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_base_path_redirect(){
+            let config = super::make_config("localhost").with_base_path("/wiki".to_string());
+
+            let request = Request::builder()
+                .uri("/wiki/hello".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), "/wiki/");
+        }
+    }
+
+    mod test_basic_auth {
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use crate::config::ProxyConfig;
+        use super::super::handle;
+
+        fn basic_auth_config(address: &str) -> Arc<ProxyConfig> {
+            Arc::new(super::make_config(address).with_basic_auth(true))
+        }
+
+        #[tokio::test]
+        async fn test_browser_is_redirected() {
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Accept", "text/html,application/xhtml+xml,*/*;q=0.8")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, basic_auth_config("localhost")).await;
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("WWW-Authenticate"), None);
+        }
+
+        #[tokio::test]
+        async fn test_api_client_is_challenged() {
+            let request = Request::builder()
+                .uri("/recipes/default/tiddlers.json".parse::<Uri>().unwrap())
+                .header("Accept", "application/json")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, basic_auth_config("localhost")).await;
+            assert_eq!(resp.status(), 401);
+            assert_eq!(
+                resp.headers().get("WWW-Authenticate").unwrap(),
+                "Basic realm=\"TiddlyProxy\", charset=\"UTF-8\""
+            );
+        }
+
+        #[tokio::test]
+        async fn test_api_client_wrong_password() {
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Accept", "application/json")
+                .header("Authorization", format!("Basic {}", base64::encode("user:wrong")))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, basic_auth_config("localhost")).await;
+            assert_eq!(resp.status(), 401);
+        }
+
+        #[tokio::test]
+        async fn test_custom_realm() {
+            let config = super::make_config("localhost")
+                .with_basic_auth(true)
+                .with_basic_auth_realm("Team wiki".to_string());
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Accept", "application/json")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(
+                resp.headers().get("WWW-Authenticate").unwrap(),
+                "Basic realm=\"Team wiki\", charset=\"UTF-8\""
+            );
+        }
+
+        #[tokio::test]
+        async fn test_wrong_password_is_delayed() {
+            let config = super::make_config("localhost").with_basic_auth(true).with_login_cooldown(Some(1));
+            let config = Arc::new(config);
+            let request = |credentials: &str| {
+                let mut request = Request::builder()
+                    .uri("/hello".parse::<Uri>().unwrap())
+                    .header("Accept", "application/json")
+                    .header("Authorization", format!("Basic {}", base64::encode(credentials)))
+                    .body(Body::empty()).unwrap();
+                request.extensions_mut().insert("127.0.0.1:50000".parse::<std::net::SocketAddr>().unwrap());
+                request
+            };
+
+            assert_eq!(handle(request("user:wrong"), config.clone()).await.status(), 401);
+            let start = Instant::now();
+            assert_eq!(handle(request("user:wrong"), config.clone()).await.status(), 401);
+            assert!(start.elapsed() >= Duration::from_secs(1));
+        }
+
+        #[tokio::test]
+        async fn test_api_client_is_redirected_when_disabled() {
+            let config = super::make_config("localhost");
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Accept", "application/json")
+                .header("Authorization", format!("Basic {}", base64::encode("user:password")))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 303);
+        }
+
+        #[tokio::test]
+        async fn test_api_client_with_credentials() {
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/hello")
+                .expect_header("X-Auth-Username", "user")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Accept", "application/json")
+                .header("Authorization", format!("Basic {}", base64::encode("user:password")))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, basic_auth_config(&format!("{}", mock_server.address()))).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+    }
+
+    mod test_idle_timeout {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use cookie::Cookie;
+        use crate::auth::Token;
+        use super::super::handle;
+
+        async fn refreshed_token(token: Token) -> Option<Token> {
+            let mock_server = MockServer::start();
+            Mock::new()
+                .expect_method(httpmock::Method::GET)
+                .expect_path("/hello")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = Arc::new(super::make_config(&format!("{}", mock_server.address()))
+                .with_idle_timeout(Some(100)));
+
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .method("GET")
+                .header("Cookie", format!("proxy_auth={}", token.generate(config.as_ref())))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            resp.headers().get("Set-Cookie").map(|cookie| {
+                let cookie = Cookie::parse(cookie.to_str().unwrap().to_string()).unwrap();
+                Token::decode(cookie.value(), config.as_ref(), 0).unwrap()
+            })
+        }
+
+        #[tokio::test]
+        async fn test_refreshing_on_activity() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 10, String::from("user")).with_max_expiration(now + 1000);
+
+            let refreshed = refreshed_token(token).await.unwrap();
+            assert_eq!(refreshed.username(), "user");
+            assert!(refreshed.expiration() >= now + 100);
+            assert_eq!(refreshed.max_expiration(), now + 1000);
+        }
+
+        #[tokio::test]
+        async fn test_absolute_expiration_cap() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 10, String::from("user")).with_max_expiration(now + 50);
+
+            let refreshed = refreshed_token(token).await.unwrap();
+            assert_eq!(refreshed.expiration(), now + 50);
+        }
+
+        #[tokio::test]
+        async fn test_no_refresh_past_cap() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 50, String::from("user")).with_max_expiration(now + 50);
+            assert_eq!(refreshed_token(token).await, None);
+        }
+
+        #[tokio::test]
+        async fn test_legacy_token_reissued() {
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 1000, String::from("user"));
+
+            let refreshed = refreshed_token(token).await.unwrap();
+            assert!(refreshed.has_max_expiration());
+            assert_eq!(refreshed.max_expiration(), now + 1000);
+            assert!(refreshed.expiration() >= now + 100 && refreshed.expiration() < now + 1000);
+        }
+    }
+
+    mod test_grace_period {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use rstest::rstest;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use cookie::Cookie;
+        use crate::auth::Token;
+        use super::super::handle;
+
+        #[rstest(expired_ago, max_expiration_in, expected, refreshed,
+            case(0, Some(3600), 200, true),
+            case(30, Some(3600), 200, true),
+            case(61, Some(3600), 303, false),
+            // Without an idle timeout the session ends at its maximum expiration, there is nothing to renew
+            case(30, None, 303, false),
+        )]
+        #[tokio::test]
+        async fn test_expired_token(expired_ago: u64, max_expiration_in: Option<u64>, expected: u16, refreshed: bool) {
+            let mock_server = MockServer::start();
+            Mock::new()
+                .expect_path("/hello")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = Arc::new(super::make_config(&format!("{}", mock_server.address()))
+                .with_grace_period(60));
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let mut token = Token::new(now - expired_ago, String::from("user"));
+            if let Some(max_expiration_in) = max_expiration_in {
+                token = token.with_max_expiration(now + max_expiration_in);
+            }
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token.generate(config.as_ref())))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), expected);
+            let cookie = resp.headers().get("Set-Cookie")
+                .map(|cookie| Cookie::parse(cookie.to_str().unwrap().to_string()).unwrap());
+            assert_eq!(cookie.is_some(), refreshed);
+            if let Some(cookie) = cookie {
+                let token = Token::decode(cookie.value(), config.as_ref(), now).unwrap();
+                assert_eq!(token.username(), "user");
+                assert!(token.expiration() > now);
+                assert_eq!(token.max_expiration(), now + max_expiration_in.unwrap());
+                let expires = cookie.expires().unwrap().unix_timestamp() as u64;
+                assert_eq!(expires, token.expiration() + 60);
+            }
+        }
+
+        #[rstest(username, denied,
+            case("user", true),
+            case("removed", false),
+        )]
+        #[tokio::test]
+        async fn test_inactive_user_not_renewed(username: &str, denied: bool) {
+            let mock_server = MockServer::start();
+            Mock::new()
+                .expect_path("/hello")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let mut config = super::make_config(&format!("{}", mock_server.address())).with_grace_period(60);
+            if denied {
+                config = config.with_denied_users(vec![username.to_string()]);
+            }
+            let config = Arc::new(config);
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now - 10, username.to_string()).with_max_expiration(now + 3600);
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token.generate(config.as_ref())))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+        }
+
+        #[tokio::test]
+        async fn test_renewed_on_internal_response() {
+            let config = Arc::new(super::make_config("localhost:1")
+                .with_grace_period(60)
+                .with_allowed_paths(Some(vec![String::from("/allowed")])));
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now - 10, String::from("user")).with_max_expiration(now + 3600);
+            let request = Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token.generate(config.as_ref())))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 403);
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string()).unwrap();
+            let token = Token::decode(cookie.value(), config.as_ref(), now).unwrap();
+            assert!(token.expiration() > now);
+        }
+
+        #[tokio::test]
+        async fn test_suspended_user_session_rejected() {
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .expect_path("/hello")
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = Arc::new(super::make_config(&format!("{}", mock_server.address())));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 3600, String::from("user")).generate(config.as_ref());
+            let request = || Request::builder()
+                .uri("/hello".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            assert_eq!(handle(request(), config.clone()).await.status(), 200);
+            config.set_users(crate::config::parse_credentials(
+                "!user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8"
+            ).unwrap()).unwrap();
+            assert_ne!(handle(request(), config.clone()).await.status(), 200);
+            assert_eq!(mock.times_called(), 1);
+        }
+    }
+
+    mod test_login_page {
+        use std::sync::Arc;
+        use http::Uri;
+        use hyper::{Request, Body};
+        use futures::stream::StreamExt;
+        use crate::config::ProxyConfig;
+        use std::time::{Duration, Instant, SystemTime};
+        use std::net::SocketAddr;
+        use rstest::rstest;
+        use crate::auth::Token;
+        use cookie::{Cookie, SameSite};
+        use crate::auth::login_csrf_token;
+        use super::super::{handle, render_login_form, login_form_response, LoginFormContext, LoginStatus};
+
+        async fn render_login_page(config: ProxyConfig) -> String {
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("GET")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 200);
+            String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap()
+        }
+
+        #[tokio::test]
+        async fn test_escaping() {
+            let html = render_login_page(super::make_config("localhost")
+                .with_login_title(Some("<script>alert(1)</script>".to_string()))
+                .with_login_message(Some("Ask \"admin\" & <b>wait</b>".to_string()))
+                .with_login_logo_url(Some("/logo.png\" onerror=\"alert(1)".to_string()))
+            ).await;
+            assert!(html.contains("<h1>&lt;script&gt;alert(1)&lt;/script&gt;</h1>"));
+            assert!(html.contains("Ask &quot;admin&quot; &amp; &lt;b&gt;wait&lt;/b&gt;"));
+            assert!(html.contains("src=\"/logo.png&quot; onerror=&quot;alert(1)\""));
+            assert!(!html.contains("<script>") && !html.contains("<b>"));
+        }
+
+        #[tokio::test]
+        async fn test_head_request() {
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("HEAD")
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(super::make_config("localhost"))).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/html");
+            let length: usize = resp.headers().get("Content-Length").unwrap()
+                .to_str().unwrap().parse().unwrap();
+            assert_eq!(length, render_login_page(super::make_config("localhost")).await.len());
+            let body = resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await;
+            assert!(body.is_empty());
+        }
+
+        #[tokio::test]
+        async fn test_username_field() {
+            let html = render_login_page(super::make_config("localhost")).await;
+            assert!(html.contains("name=\"username\""));
+            assert!(html.contains("name=\"password\""));
+        }
+
+        #[tokio::test]
+        async fn test_password_only_form() {
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                None, None
+            ).unwrap();
+            let html = render_login_page(config).await;
+            assert!(!html.contains("name=\"username\""));
+            assert!(html.contains("name=\"password\""));
+        }
+
+        #[tokio::test]
+        async fn test_default_branding() {
+            let html = render_login_page(super::make_config("localhost")).await;
+            assert!(html.contains("<title>Login</title>"));
+            assert!(!html.contains("<h1>"));
+            assert!(!html.contains("<img"));
+        }
+
+        #[tokio::test]
+        async fn test_custom_branding() {
+            let config = super::make_config("localhost")
+                .with_login_title(Some("Team Wiki Login".to_string()))
+                .with_login_logo_url(Some("/logo.png".to_string()))
+                .with_login_message(Some("Ask Finn for an account".to_string()));
+
+            let html = render_login_page(config).await;
+            assert!(html.contains("<title>Team Wiki Login</title>"));
+            assert!(html.contains("<h1>Team Wiki Login</h1>"));
+            assert!(html.contains("src=\"/logo.png\""));
+            assert!(html.contains("Ask Finn for an account"));
+        }
+
+        #[tokio::test]
+        async fn test_offline_banner() {
+            // Nothing listens on a port that was just released
+            let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+            let config = Arc::new(super::make_config(&address.to_string())
+                .with_offline_banner(Some("The wiki is offline".to_string())));
+            let login_page = || async {
+                let request = Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .body(Body::empty()).unwrap();
+                let resp = handle(request, config.clone()).await;
+                String::from_utf8(resp.into_body()
+                    .map(|c| c.unwrap().to_vec())
+                    .concat().await).unwrap()
+            };
+            assert!(!login_page().await.contains("The wiki is offline"));
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 502);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(body.contains("<div class=\"error\">The wiki is offline</div>"));
+
+            assert!(login_page().await.contains("<div class=\"error\">The wiki is offline</div>"));
+        }
+
+        #[tokio::test]
+        async fn test_offline_banner_ignores_relayed_bad_gateway() {
+            let mock_server = httpmock::MockServer::start();
+            httpmock::Mock::new()
+                .return_status(502)
+                .create_on(&mock_server);
+            let config = Arc::new(super::make_config(&format!("{}", mock_server.address()))
+                .with_offline_banner(Some("The wiki is offline".to_string())));
+
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            assert_eq!(handle(request, config.clone()).await.status(), 502);
+            assert_eq!(config.offline_banner(config.remote_uri()), None);
+        }
+
+        #[tokio::test]
+        async fn test_login_csrf_round_trip() {
+            let config = Arc::new(super::make_config("localhost"));
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            let cookie = Cookie::parse(resp.headers().get("Set-Cookie").unwrap().to_str().unwrap().to_string()).unwrap();
+            assert_eq!(cookie.name(), "proxy_login_csrf");
+            assert_eq!(cookie.http_only(), Some(true));
+            assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+            let html = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert!(html.contains(&format!("name=\"csrf_token\" value=\"{}\"", cookie.value())));
+
+            let request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST")
+                .header("Cookie", format!("proxy_login_csrf={}", cookie.value()))
+                .body(Body::from(format!("username=user&password=password&csrf_token={}", cookie.value()))).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 303);
+        }
+
+        #[rstest(cookie, field,
+            case(None, None),
+            case(Some("valid"), None),
+            case(None, Some("valid")),
+            case(Some("valid"), Some("other")),
+            case(Some("tampered"), Some("tampered")),
+            case(Some("expired"), Some("expired")),
+        )]
+        #[tokio::test]
+        async fn test_login_csrf_rejected(cookie: Option<&str>, field: Option<&str>) {
+            let config = super::make_config("localhost");
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = |kind: &str| match kind {
+                "valid" => login_csrf_token("nonce", now + 60, &config),
+                "other" => login_csrf_token("other", now + 60, &config),
+                "tampered" => login_csrf_token("nonce", now + 60, &config).replace("nonce", "tampered"),
+                _ => login_csrf_token("nonce", now - 1, &config)
+            };
+
+            let (cookie, field) = (cookie.map(token), field.map(token));
+
+            let mut request = Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST");
+            if let Some(cookie) = cookie {
+                request = request.header("Cookie", format!("proxy_login_csrf={}", cookie));
+            }
+            let mut body = String::from("username=user&password=password");
+            if let Some(field) = field {
+                body.push_str(&format!("&csrf_token={}", field));
+            }
+            let resp = handle(request.body(Body::from(body)).unwrap(), Arc::new(config)).await;
+            assert_eq!(resp.status(), 400);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+        }
+
+        #[tokio::test]
+        async fn test_oversized_login_form() {
+            let config = super::make_config("localhost");
+            let padding = "a".repeat(super::super::MAX_FORM_SIZE);
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, &format!("padding={}&username=user&password=password", padding));
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), 400);
+        }
+
+        #[tokio::test]
+        async fn test_login_cooldown() {
+            let config = Arc::new(super::make_config("localhost").with_login_cooldown(Some(1)));
+            let login = |password: &str| {
+                let mut request = super::login_form(Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .method("POST"), &config, &format!("username=user&password={}", password));
+                request.extensions_mut().insert("127.0.0.1:50000".parse::<SocketAddr>().unwrap());
+                let config = config.clone();
+                async move {
+                    let start = Instant::now();
+                    let resp = handle(request, config).await;
+                    (resp.status(), start.elapsed())
+                }
+            };
+
+            let (status, first) = login("wrong").await;
+            assert_eq!(status, 200);
+            assert!(first < Duration::from_millis(500));
+            for _ in 0..2 {
+                let (status, elapsed) = login("wrong").await;
+                assert_eq!(status, 200);
+                assert!(elapsed >= Duration::from_secs(1));
+            }
+
+            assert_eq!(login("password").await.0, 303);
+            assert!(login("wrong").await.1 < Duration::from_millis(500));
+        }
+
+        #[rstest(username, address, delayed,
+            case("user", "127.0.0.2:50000", true),
+            case("unknown", "127.0.0.2:50000", false),
+            case("user", "", false),
+        )]
+        #[tokio::test]
+        async fn test_login_cooldown_by_username(username: &str, address: &str, delayed: bool) {
+            let config = Arc::new(super::make_config("localhost").with_login_cooldown(Some(1)));
+            let login = |address: &str| {
+                let mut request = super::login_form(Request::builder()
+                    .uri("/".parse::<Uri>().unwrap())
+                    .method("POST"), &config, &format!("username={}&password=wrong", username));
+                if !address.is_empty() {
+                    request.extensions_mut().insert(address.parse::<SocketAddr>().unwrap());
+                }
+                let config = config.clone();
+                async move {
+                    let start = Instant::now();
+                    handle(request, config).await;
+                    start.elapsed()
+                }
+            };
+
+            // Another address already failed to log in with the same username
+            login("127.0.0.1:50000").await;
+            assert_eq!(login(address).await >= Duration::from_secs(1), delayed);
+        }
+
+        #[rstest(status, wrong, locked,
+            case(LoginStatus::Ok, false, false),
+            case(LoginStatus::WrongCredentials, true, false),
+            case(LoginStatus::RateLimited { retry_after: 42 }, false, true),
+        )]
+        #[tokio::test]
+        async fn test_login_status(status: LoginStatus, wrong: bool, locked: bool) {
+            let resp = login_form_response(&super::make_config("localhost"), status, false, false);
+            assert_eq!(resp.status() == 429, locked);
+            assert_eq!(resp.headers().get("Retry-After").is_some(), locked);
+            let html = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(html.contains("The username or password is incorrect"), wrong);
+            assert_eq!(html.contains("try again in 42 seconds"), locked);
+        }
+
+        #[rstest(source,
+            case("<form>{ login_url </form>"),
+            case("{{ if requires_username }}<input>"),
+            case("{ missing_field }"),
+        )]
+        fn test_broken_template(source: &str) {
+            let context = LoginFormContext{
+                status: LoginStatus::Ok,
+                csrf_token: "token",
+                requires_username: true,
+                styles_url: "/wiki/proxy:styles.css".to_string(),
+                login_url: "/wiki/\"login".to_string(),
+                title: "Login",
+                heading: None,
+                logo_url: None,
+                message: None,
+                offline_banner: None,
+                inline_styles: None
+            };
+            let html = render_login_form(source, &context);
+            assert!(html.contains("action=\"/wiki/&quot;login\""));
+            assert!(html.contains("name=\"username\""));
+            assert!(html.contains("name=\"password\""));
+            assert!(html.contains("name=\"csrf_token\" value=\"token\""));
+        }
+    }
+
+    mod test_path_traversal {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use rstest::rstest;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use crate::auth::Token;
+        use super::super::{handle, has_path_traversal};
+
+        #[rstest(path, expected,
+            case("/recipes/default/tiddlers/Note", false),
+            case("/a/../../b", true),
+            case("/..", true),
+            case("/..%2f..%2fetc", true),
+            case("/%2E%2E/etc", true),
+            case("/a/..%5Cb", true),
+            case("/recipes/default/tiddlers/Chapter..2", false),
+            case("/recipes/default/tiddlers/Wait...", false),
+            case("/recipes/default/tiddlers/..hidden", false),
+            case("/a/./b", false),
+        )]
+        fn test_has_path_traversal(path: &str, expected: bool) {
+            assert_eq!(has_path_traversal(path), expected);
+        }
+
+        #[rstest(path, reject, expected,
+            case("/..%2f..%2fetc", true, 400),
+            case("/recipes/default/tiddlers/Wait..%20what", true, 200),
+            case("/recipes/default/tiddlers/%2E%2E", false, 200),
+        )]
+        #[tokio::test]
+        async fn test_rejecting_path_traversal(path: &str, reject: bool, expected: u16) {
+            let mock_server = MockServer::start();
+            let mock = Mock::new()
+                .return_body("remote content")
+                .create_on(&mock_server);
+
+            let config = super::make_config(&format!("{}", mock_server.address()))
+                .with_reject_path_traversal(reject);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            let request = Request::builder()
+                .uri(path.parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+
+            let resp = handle(request, Arc::new(config)).await;
+            assert_eq!(resp.status(), expected);
+            assert_eq!(mock.times_called(), if expected == 200 { 1 } else { 0 });
+        }
+    }
+
+    mod test_stripping_base_path {
+        use rstest::rstest;
+        use http::Uri;
+        use super::super::strip_base_path;
+
+        #[rstest(uri, base_path, expected,
+            case("/hello", "", Some("/hello")),
+            case("/wiki", "/wiki", Some("/")),
+            case("/wiki/", "/wiki", Some("/")),
+            case("/wiki/hello?a=1", "/wiki", Some("/hello?a=1")),
+            case("/wikipedia", "/wiki", None),
+            case("/hello", "/wiki", None),
+        )]
+        fn test_strip_base_path(uri: &str, base_path: &str, expected: Option<&str>) {
+            assert_eq!(
+                strip_base_path(&uri.parse::<Uri>().unwrap(), base_path),
+                expected.map(|uri| uri.parse::<Uri>().unwrap())
+            );
+        }
+    }
+
+    mod test_redirect {
+        use rstest::rstest;
+        use hyper::{Body, Request};
+        use crate::config::ProxyConfig;
+        use super::super::{redirect, https_redirect};
+
+        #[rstest(location, expected,
+            case("/wiki/", "/wiki/"),
+            case("/wiki/\r\nSet-Cookie: proxy_auth=forged", "/"),
+            case("/wiki/\nX-Injected: 1", "/"),
+            case("/wiki/\r", "/"),
+        )]
+        fn test_redirect_location(location: &str, expected: &str) {
+            let config = ProxyConfig::from_values(
+                "localhost",
+                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                None, None
+            ).unwrap();
+            let resp = redirect(&config, location).body(Body::empty()).unwrap();
+            assert_eq!(resp.status(), 303);
+            assert_eq!(resp.headers().get("Location").unwrap(), expected);
+            assert!(resp.headers().get("Set-Cookie").is_none());
+            assert!(resp.headers().get("X-Injected").is_none());
+        }
+
+        #[rstest(host, uri, port, expected,
+            case(Some("wiki.example.com"), "/", 443, Some("https://wiki.example.com/")),
+            case(Some("wiki.example.com:8080"), "/a/b?c=d", 443, Some("https://wiki.example.com/a/b?c=d")),
+            case(Some("[::1]:8080"), "/", 443, Some("https://[::1]/")),
+            case(Some("wiki.example.com:8080"), "/a?b=c", 8443, Some("https://wiki.example.com:8443/a?b=c")),
+            case(Some("[::1]:8080"), "/", 8443, Some("https://[::1]:8443/")),
+            case(Some("wiki.example.com/evil"), "/", 443, None),
+            case(None, "/", 443, None),
+        )]
+        fn test_https_redirect(host: Option<&str>, uri: &str, port: u16, expected: Option<&str>) {
+            let mut request = Request::builder().uri(uri);
+            if let Some(host) = host {
+                request = request.header("Host", host);
+            }
+            let resp = https_redirect(&request.body(Body::empty()).unwrap(), port);
+            match expected {
+                Some(location) => {
+                    assert_eq!(resp.status(), 301);
+                    assert_eq!(resp.headers().get("Location").unwrap(), location);
+                },
+                None => assert_eq!(resp.status(), 400)
+            }
+        }
+    }
+
+    mod test_routes {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use futures::stream::StreamExt;
+        use crate::auth::Token;
+        use super::super::handle;
+
+        #[tokio::test]
+        async fn test_routing_to_upstreams() {
+            let servers = [MockServer::start(), MockServer::start()];
+            let mocks: Vec<_> = servers.iter().zip(["/x", "/y"].iter())
+                .map(|(server, path)| Mock::new()
+                    .expect_method(httpmock::Method::GET)
+                    .expect_path(path)
+                    .return_body(&format!("content of {}", path))
+                    .create_on(server))
+                .collect();
+
+            let uri = |server: &MockServer| format!("http://{}/", server.address()).parse::<Uri>().unwrap();
+            let config = Arc::new(super::make_config(&format!("{}", servers[0].address())).with_routes(vec![
+                ("/a".to_string(), uri(&servers[0])),
+                ("/b".to_string(), uri(&servers[1]))
+            ]));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
+
+            for (path, expected) in [("/a/x", Some("content of /x")), ("/b/y", Some("content of /y")), ("/c/x", None)] {
+                let request = Request::builder()
+                    .uri(path.parse::<Uri>().unwrap())
+                    .header("Cookie", format!("proxy_auth={}", token))
+                    .body(Body::empty()).unwrap();
+                let resp = handle(request, config.clone()).await;
+                match expected {
+                    Some(expected) => {
+                        assert_eq!(resp.status(), 200);
+                        let body = String::from_utf8(resp.into_body()
+                            .map(|c| c.unwrap().to_vec())
+                            .concat().await).unwrap();
+                        assert_eq!(body, expected);
+                    },
+                    None => assert_eq!(resp.status(), 404)
+                }
+            }
+            assert_eq!(mocks[0].times_called(), 1);
+            assert_eq!(mocks[1].times_called(), 1);
+        }
+
+        #[tokio::test]
+        async fn test_routed_cookie_path() {
+            let server = MockServer::start();
+            Mock::new()
+                .expect_path("/wiki/x")
+                .return_header("Set-Cookie", "theme=dark; Path=/wiki/")
+                .create_on(&server);
+
+            let uri = format!("http://{}/wiki/", server.address()).parse::<Uri>().unwrap();
+            let config = Arc::new(super::make_config(&format!("{}", server.address()))
+                .with_routes(vec![("/notes".to_string(), uri)])
+                .with_base_path("/proxy".to_string())
+                .with_rewrite_cookies(true));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
+
+            let request = Request::builder()
+                .uri("/proxy/notes/x".parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::empty()).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 200);
+            let cookie = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap();
+            assert_eq!(cookie::Cookie::parse(cookie).unwrap().path(), Some("/proxy/notes"));
+        }
+
+        #[tokio::test]
+        async fn test_connect_rejected() {
+            let server = MockServer::start();
+            let mock = Mock::new().return_body("remote content").create_on(&server);
+            let config = Arc::new(super::make_config(&format!("{}", server.address())));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
 
-            let request = Request::builder()
-                .header("Cookie", format!(
-                    "cookie1=2; proxy_auth={}; cookie2=3",
-                    Token::new(now + 100, String::from("user")).generate(&config)
-                ))
-                .body(())
-                .unwrap();
-            assert_eq!(get_username(&request, &config), Some(String::from("user")));
+            for cookie in [None, Some(format!("proxy_auth={}", token))] {
+                let mut request = Request::builder()
+                    .method("CONNECT")
+                    .uri("example.com:443".parse::<Uri>().unwrap());
+                if let Some(cookie) = cookie {
+                    request = request.header("Cookie", cookie);
+                }
+                let resp = handle(request.body(Body::empty()).unwrap(), config.clone()).await;
+                assert_eq!(resp.status(), 405);
+                assert!(resp.headers().get("Allow").is_some());
+                let body = String::from_utf8(resp.into_body()
+                    .map(|c| c.unwrap().to_vec())
+                    .concat().await).unwrap();
+                assert!(body.contains("not a forward proxy"));
+            }
+            assert_eq!(mock.times_called(), 0);
         }
     }
 
-    mod test_navigation {
+    mod test_request_id {
         use std::sync::Arc;
+        use std::time::SystemTime;
+        use rstest::rstest;
         use http::Uri;
-        use httpmock::{Mock, MockServer};
-        use hyper::{Request, Body};
-        use crate::config::ProxyConfig;
+        use hyper::{Request, Response, Body};
+        use futures::stream::StreamExt;
         use crate::auth::Token;
         use super::super::handle;
-        use std::time::SystemTime;
-        use futures::stream::StreamExt;
-        use cookie::Cookie;
 
-        #[tokio::test]
-        async fn test_redirecting_unauthenticated_to_login_page(){
-            let mock_server = MockServer::start();
-            let config = ProxyConfig::from_values(
-                &format!("{}", mock_server.address()),
-                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
-                None, None
-            ).unwrap();
+        fn echo_server() -> String {
+            // Answers with the request id the wiki server received
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let server = hyper::Server::from_tcp(listener).unwrap()
+                .serve(hyper::service::make_service_fn(|_| async {
+                    Ok::<_, std::convert::Infallible>(hyper::service::service_fn(|request: Request<Body>| async move {
+                        let id = request.headers().get("X-Request-Id")
+                            .map(|value| value.to_str().unwrap().to_string())
+                            .unwrap_or_default();
+                        Ok::<_, std::convert::Infallible>(Response::new(Body::from(id)))
+                    }))
+                }));
+            tokio::spawn(server);
+            address
+        }
 
-            let mock = Mock::new()
-                .expect_method(httpmock::Method::GET)
-                .expect_path("/hello")
-                .create_on(&mock_server);
+        #[rstest(trusted, incoming, kept,
+            case(false, None, false),
+            case(false, Some("abc-123"), false),
+            case(true, Some("abc-123"), true),
+            case(true, Some("abc 123"), false),
+            case(true, None, false),
+        )]
+        #[tokio::test]
+        async fn test_request_id(trusted: bool, incoming: Option<&str>, kept: bool) {
+            let config = Arc::new(super::make_config(&echo_server()).with_trusted_proxy(trusted));
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
 
-            let request = Request::builder()
+            let mut request = Request::builder()
                 .uri("/hello".parse::<Uri>().unwrap())
-                .method("GET")
-                .body(Body::empty()).unwrap();
-
-            let resp = handle(request, Arc::new(config)).await;
-            assert_eq!(resp.status(), 303);
-            assert_eq!(resp.headers().get("Location").unwrap(), "/");
-            assert_eq!(mock.times_called(), 0);
+                .header("Cookie", format!("proxy_auth={}", token));
+            if let Some(incoming) = incoming {
+                request = request.header("X-Request-Id", incoming);
+            }
+            let resp = handle(request.body(Body::empty()).unwrap(), config).await;
+            assert_eq!(resp.status(), 200);
+            let id = resp.headers().get("X-Request-Id").unwrap().to_str().unwrap().to_string();
+            let forwarded = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(forwarded, id);
+            if kept {
+                assert_eq!(Some(id.as_str()), incoming);
+            } else {
+                assert_eq!(id.len(), 16);
+                assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+            }
         }
 
         #[tokio::test]
-        async fn test_running_proxy_when_authenticated(){
-            let mock_server = MockServer::start();
-            let config = ProxyConfig::from_values(
-                &format!("{}", mock_server.address()),
-                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
-                None, None
-            ).unwrap();
+        async fn test_unique_ids() {
+            let config = Arc::new(super::make_config("localhost"));
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let request = Request::builder().uri("/".parse::<Uri>().unwrap()).body(Body::empty()).unwrap();
+                let resp = handle(request, config.clone()).await;
+                ids.push(resp.headers().get("X-Request-Id").unwrap().clone());
+            }
+            assert_ne!(ids[0], ids[1]);
+        }
+    }
 
-            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-            let token = Token::new(now + 100, String::from("user")).generate(&config);
+    mod test_canary {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use rstest::rstest;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use hyper::header::HeaderName;
+        use futures::stream::StreamExt;
+        use crate::auth::Token;
+        use super::super::handle;
 
-            let mock = Mock::new()
-                .expect_method(httpmock::Method::GET)
-                .expect_path("/hello")
-                .expect_header("X-Auth-Username", "user")
-                .return_body("remote content")
-                .create_on(&mock_server);
+        #[rstest(roles, header, path, expected,
+            case(&[], None, "/x", "stable"),
+            case(&["canary"], None, "/x", "canary"),
+            case(&["editor", "canary"], None, "/x", "canary"),
+            case(&["editor"], None, "/x", "stable"),
+            case(&[], Some("X-Canary"), "/x", "canary"),
+            case(&["canary"], None, "/team/x", "team"),
+        )]
+        #[tokio::test]
+        async fn test_canary_routing(roles: &[&str], header: Option<&str>, path: &str, expected: &str) {
+            let servers = [MockServer::start(), MockServer::start(), MockServer::start()];
+            let _mocks: Vec<_> = servers.iter().zip(["stable", "canary", "team"].iter())
+                .map(|(server, name)| Mock::new()
+                    .expect_path("/x")
+                    .return_body(name)
+                    .create_on(server))
+                .collect();
 
-            let request = Request::builder()
-                .uri("/hello".parse::<Uri>().unwrap())
-                .method("GET")
-                .header("Cookie", format!("proxy_auth={}", token))
-                .body(Body::empty()).unwrap();
+            let uri = |server: &MockServer| format!("http://{}/", server.address()).parse::<Uri>().unwrap();
+            let config = Arc::new(super::make_config(&format!("{}", servers[0].address())).with_routes(vec![
+                (String::new(), uri(&servers[0])),
+                ("/team".to_string(), uri(&servers[2]))
+            ]).with_canary(Some(uri(&servers[1])), Some(HeaderName::from_static("x-canary"))));
 
-            let resp = handle(request, Arc::new(config)).await;
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user"))
+                .with_roles(roles.iter().map(|role| role.to_string()).collect())
+                .generate(config.as_ref());
+            let mut request = Request::builder()
+                .uri(path.parse::<Uri>().unwrap())
+                .header("Cookie", format!("proxy_auth={}", token));
+            if let Some(header) = header {
+                request = request.header(header, "1");
+            }
+
+            let resp = handle(request.body(Body::empty()).unwrap(), config).await;
             assert_eq!(resp.status(), 200);
             let body = String::from_utf8(resp.into_body()
                 .map(|c| c.unwrap().to_vec())
                 .concat().await).unwrap();
-            assert_eq!(body, "remote content");
-            assert_eq!(mock.times_called(), 1);
+            assert_eq!(body, expected);
         }
+    }
 
-        #[tokio::test]
-        async fn test_logging_out(){
+    mod test_echo {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use http::Uri;
+        use hyper::{Request, Body, Response};
+        use crate::auth::Token;
+        use crate::config::ProxyConfig;
+        use super::super::handle;
+
+        async fn request_echo(path: &str, username: Option<&str>) -> Response<Body> {
             let config = ProxyConfig::from_values(
-                "localhost",
+                "http://localhost:8080/wiki/",
                 "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:abcdef:291e247d155354e48fec2b579637782446821935fc96a5a08a0b7885179c408b",
+                "admin:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8:admin;\
+                 user:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
                 None, None
-            ).unwrap();
-            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-            let token = Token::new(now + 100, String::from("user")).generate(&config);
+            ).unwrap().with_routes(vec![
+                ("".to_string(), "http://localhost:8080/wiki/".parse().unwrap()),
+                ("/notes".to_string(), "http://localhost:9000/".parse().unwrap())
+            ]);
+            let mut request = Request::builder()
+                .uri(path.parse::<Uri>().unwrap())
+                .header("X-Auth-Username", "spoofed");
+            if let Some(username) = username {
+                // Only the configured roles count, not the ones in the token
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+                let token = Token::new(now + 100, String::from(username))
+                    .with_roles(vec!["admin".to_string()]).generate(&config);
+                request = request.header("Cookie", format!("proxy_auth={}", token));
+            }
+            handle(request.body(Body::empty()).unwrap(), Arc::new(config)).await
+        }
 
-            let request = Request::builder()
-                .uri("/logout".parse::<Uri>().unwrap())
-                .method("GET")
-                .header("Cookie", format!("proxy_auth={}", token))
-                .body(Body::empty()).unwrap();
+        #[tokio::test]
+        async fn test_echo() {
+            let resp = request_echo("/proxy:echo/hello?q=1", Some("admin")).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("Content-Type").unwrap(), "application/json");
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let echo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(echo["method"], "GET");
+            assert_eq!(echo["uri"], "http://localhost:8080/wiki/hello?q=1");
+            let headers = echo["headers"].as_array().unwrap();
+            let header = |name: &str| headers.iter()
+                .filter(|header| header[0] == name)
+                .map(|header| header[1].as_str().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(header("x-auth-username"), vec!["admin"]);
+            assert_eq!(header("x-auth-roles"), vec!["admin"]);
+            assert_eq!(header("cookie"), vec!["[redacted]"]);
+        }
 
-            let resp = handle(request, Arc::new(config)).await;
-            assert_eq!(resp.status(), 303);
-            assert_eq!(resp.headers().get("Location").unwrap(), "/");
-            assert_eq!(
-                resp.headers().get("Set-Cookie").unwrap(),
-                "proxy_auth=; HttpOnly; Path=/; Expires=Thu, 01 Jan 1970 00:00:00 GMT"
-            );
+        #[tokio::test]
+        async fn test_echo_routed() {
+            let resp = request_echo("/proxy:echo/notes/today", Some("admin")).await;
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let echo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(echo["uri"], "http://localhost:9000/today");
+
+            let resp = request_echo("/proxy:echo", Some("admin")).await;
+            let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+            let echo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(echo["uri"], "http://localhost:8080/wiki/");
         }
 
         #[tokio::test]
-        async fn test_logging_in_wrong_password(){
-            let config = ProxyConfig::from_values(
+        async fn test_echo_requires_admin() {
+            assert_eq!(request_echo("/proxy:echo/hello", Some("user")).await.status(), 403);
+            assert_eq!(request_echo("/proxy:echo/hello", None).await.status(), 303);
+            assert_eq!(request_echo("/proxy:echoes", Some("admin")).await.status(), 404);
+        }
+    }
+
+    mod test_admin {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use http::Uri;
+        use hyper::{Request, Body, Response};
+        use cookie::Cookie;
+        use crate::auth::{Token, csrf_token};
+        use crate::config::ProxyConfig;
+        use crate::credentials::CredentialsStore;
+        use super::super::handle;
+
+        fn admin_config() -> Arc<ProxyConfig> {
+            Arc::new(ProxyConfig::from_values(
                 "localhost",
                 "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+                "admin:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8:admin;\
+                 user:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
                 None, None
-            ).unwrap();
+            ).unwrap().with_basic_auth(true))
+        }
 
+        async fn change_password(config: &Arc<ProxyConfig>, credentials: &str, body: &str) -> Response<Body> {
             let request = Request::builder()
-                .uri("/".parse::<Uri>().unwrap())
+                .uri("/proxy:admin/passwd".parse::<Uri>().unwrap())
                 .method("POST")
-                .body(Body::from("username=user&password=wrong_password")).unwrap();
+                .header("Authorization", format!("Basic {}", base64::encode(credentials)))
+                .body(Body::from(body.to_string())).unwrap();
+            handle(request, config.clone()).await
+        }
 
-            let resp = handle(request, Arc::new(config)).await;
+        async fn login(config: &Arc<ProxyConfig>, username: &str, password: &str) -> u16 {
+            let request = super::login_form(Request::builder()
+                .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), config, &format!("username={}&password={}", username, password));
+            handle(request, config.clone()).await.status().as_u16()
+        }
+
+        #[tokio::test]
+        async fn test_changing_password() {
+            let config = admin_config();
+            assert_eq!(login(&config, "user", "another").await, 303);
+
+            let resp = change_password(&config, "admin:password", "username=user&password=changed").await;
             assert_eq!(resp.status(), 200);
-            assert_eq!(resp.headers().get("Set-Cookie"), None);
+            assert_eq!(login(&config, "user", "changed").await, 303);
+            assert_eq!(login(&config, "user", "another").await, 200);
+
+            let resp = change_password(&config, "admin:password", "username=nobody&password=changed").await;
+            assert_eq!(resp.status(), 404);
+            let resp = change_password(&config, "admin:password", "username=user&password=").await;
+            assert_eq!(resp.status(), 400);
         }
 
         #[tokio::test]
-        async fn test_logging_missing_username(){
-            let config = ProxyConfig::from_values(
-                "localhost",
-                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
-                None, None
-            ).unwrap();
+        async fn test_non_admin_is_forbidden() {
+            let config = admin_config();
+            let resp = change_password(&config, "user:another", "username=admin&password=changed").await;
+            assert_eq!(resp.status(), 403);
+            assert!(config.can_login(Some("admin"), "password"));
 
             let request = Request::builder()
+                .uri("/proxy:admin/passwd".parse::<Uri>().unwrap())
+                .method("POST")
+                .body(Body::from("username=admin&password=changed")).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 401);
+            assert!(config.can_login(Some("admin"), "password"));
+        }
+
+        #[tokio::test]
+        async fn test_session_requires_csrf_token() {
+            let config = admin_config();
+            let request = super::login_form(Request::builder()
                 .uri("/".parse::<Uri>().unwrap())
+                .method("POST"), &config, "username=admin&password=password");
+            let resp = handle(request, config.clone()).await;
+            let cookie = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap();
+            let token = String::from(Cookie::parse(cookie).unwrap().value());
+
+            for (csrf, expected) in [(String::new(), 403), (csrf_token(&token, config.as_ref()), 200)] {
+                let request = Request::builder()
+                    .uri("/proxy:admin/passwd".parse::<Uri>().unwrap())
+                    .method("POST")
+                    .header("Cookie", format!("proxy_auth={}", token))
+                    .body(Body::from(format!("username=user&password=changed&csrf_token={}", csrf))).unwrap();
+                let resp = handle(request, config.clone()).await;
+                assert_eq!(resp.status(), expected);
+            }
+            assert!(config.can_login(Some("user"), "changed"));
+        }
+
+        #[tokio::test]
+        async fn test_roles_are_checked_on_request() {
+            let config = admin_config();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user"))
+                .with_roles(vec!["admin".to_string()]).generate(config.as_ref());
+            let request = Request::builder()
+                .uri("/proxy:admin/passwd".parse::<Uri>().unwrap())
                 .method("POST")
-                .body(Body::from("password=password")).unwrap();
+                .header("Cookie", format!("proxy_auth={}", token))
+                .body(Body::from(format!("username=admin&password=changed&csrf_token={}",
+                    csrf_token(&token, config.as_ref())))).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 403);
+            assert!(config.can_login(Some("admin"), "password"));
+        }
 
-            let resp = handle(request, Arc::new(config)).await;
-            assert_eq!(resp.status(), 200);
-            assert_eq!(resp.headers().get("Set-Cookie"), None);
+        #[tokio::test]
+        async fn test_authorization_header_does_not_skip_csrf_token() {
+            let config = admin_config();
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("admin")).generate(config.as_ref());
+            let request = Request::builder()
+                .uri("/proxy:admin/passwd".parse::<Uri>().unwrap())
+                .method("POST")
+                .header("Cookie", format!("proxy_auth={}", token))
+                .header("Authorization", "Bearer anything")
+                .body(Body::from("username=user&password=changed")).unwrap();
+            let resp = handle(request, config.clone()).await;
+            assert_eq!(resp.status(), 403);
+            assert!(config.can_login(Some("user"), "another"));
         }
+    }
 
+    mod test_internal_routes {
+        use std::sync::Arc;
+        use std::time::SystemTime;
+        use futures::stream::StreamExt;
+        use rstest::rstest;
+        use http::Uri;
+        use httpmock::{Mock, MockServer};
+        use hyper::{Request, Body};
+        use crate::config::ProxyConfig;
+        use crate::auth::Token;
+        use super::super::handle;
 
+        #[rstest(method, path, authenticated, expected,
+            case("GET", "/proxy:foo", false, 404),
+            case("GET", "/proxy:foo", true, 404),
+            case("GET", "/proxy%3Afoo", true, 404),
+            case("POST", "/proxy:foo", true, 404),
+            case("GET", "/proxy:styles.css", false, 200),
+            case("GET", "/proxy:styles.css", true, 200),
+            case("HEAD", "/proxy:styles.css", true, 200),
+            case("POST", "/proxy:styles.css", false, 405),
+            case("PUT", "/proxy:styles.css", true, 405),
+        )]
         #[tokio::test]
-        async fn test_logging_in(){
+        async fn test_internal_route(method: &str, path: &str, authenticated: bool, expected: u16) {
             let mock_server = MockServer::start();
             let mock = Mock::new()
-                .expect_method(httpmock::Method::GET)
-                .expect_path("/")
-                .expect_header("X-Auth-Username", "user")
                 .return_body("remote content")
                 .create_on(&mock_server);
 
-            let config = ProxyConfig::from_values(
-                &format!("{}", mock_server.address()),
-                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                "user:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
-                None, None
-            ).unwrap();
+            let config = super::make_config(&format!("{}", mock_server.address()));
+            let mut request = Request::builder()
+                .uri(path.parse::<Uri>().unwrap())
+                .method(method);
+            if authenticated {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+                let token = Token::new(now + 100, String::from("user")).generate(&config);
+                request = request.header("Cookie", format!("proxy_auth={}", token));
+            }
 
-            let request = Request::builder()
-                .uri("/".parse::<Uri>().unwrap())
-                .method("POST")
-                .body(Body::from("username=user&password=password")).unwrap();
+            let resp = handle(request.body(Body::empty()).unwrap(), Arc::new(config)).await;
+            assert_eq!(resp.status(), expected);
+            if expected == 405 {
+                assert_eq!(resp.headers().get("Allow").unwrap(), "GET, HEAD");
+            }
+            assert_eq!(mock.times_called(), 0);
+        }
 
+        #[rstest(private_assets, authenticated, expected,
+            case(false, false, 200),
+            case(false, true, 200),
+            case(true, false, 303),
+            case(true, true, 200),
+        )]
+        #[tokio::test]
+        async fn test_private_assets(private_assets: bool, authenticated: bool, expected: u16) {
+            let config = super::make_config("localhost").with_private_assets(private_assets);
+            let mut request = Request::builder().uri("/proxy:styles.css".parse::<Uri>().unwrap());
+            if authenticated {
+                let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+                let token = Token::new(now + 100, String::from("user")).generate(&config);
+                request = request.header("Cookie", format!("proxy_auth={}", token));
+            }
             let config = Arc::new(config);
-            let resp = handle(request, config.clone()).await;
-            assert_eq!(resp.status(), 303);
-            assert_eq!(resp.headers().get("Location").unwrap(), "/");
-            assert_eq!(mock.times_called(), 0);
+            let resp = handle(request.body(Body::empty()).unwrap(), config.clone()).await;
+            assert_eq!(resp.status(), expected);
+            let body = String::from_utf8(resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(body == super::super::STYLES, expected == 200);
 
-            let cookie = resp.headers().get("Set-Cookie").unwrap().to_str().unwrap();
-            let token = String::from(Cookie::parse(cookie).unwrap().value());
+            let login_page = handle(Request::builder().body(Body::empty()).unwrap(), config).await;
+            let html = String::from_utf8(login_page.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await).unwrap();
+            assert_eq!(html.contains("proxy:styles.css"), !private_assets);
+            assert_eq!(html.contains(&format!("<style>{}</style>", super::super::STYLES)), private_assets);
+        }
 
-            let request = Request::builder()
-                .uri("/".parse::<Uri>().unwrap())
-                .method("GET")
-                .header("Cookie", format!("proxy_auth={}", token))
-                .body(Body::empty()).unwrap();
+        #[rstest(private_assets, case(false), case(true))]
+        #[tokio::test]
+        async fn test_private_assets_on_other_pages(private_assets: bool) {
+            let config = Arc::new(super::make_config("localhost").with_private_assets(private_assets));
+            config.set_maintenance(true);
+            let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let token = Token::new(now + 100, String::from("user")).generate(config.as_ref());
 
-            let resp = handle(request, config.clone()).await;
-            assert_eq!(resp.status(), 200);
-            assert_eq!(mock.times_called(), 1);
+            for (method, path, status) in [("GET", "/logout", 200), ("POST", "/", 503)] {
+                let request = Request::builder()
+                    .method(method)
+                    .uri(path.parse::<Uri>().unwrap())
+                    .header("Cookie", format!("proxy_auth={}", token))
+                    .body(Body::empty()).unwrap();
+                let resp = handle(request, config.clone()).await;
+                assert_eq!(resp.status(), status);
+                let html = String::from_utf8(resp.into_body()
+                    .map(|c| c.unwrap().to_vec())
+                    .concat().await).unwrap();
+                assert_eq!(html.contains("proxy:styles.css"), !private_assets);
+                assert_eq!(html.contains(&format!("<style>{}</style>", super::super::STYLES)), private_assets);
+            }
+        }
+
+        fn styles_request(if_none_match: Option<&str>) -> Request<Body> {
+            let mut request = Request::builder().uri("/proxy:styles.css".parse::<Uri>().unwrap());
+            if let Some(etag) = if_none_match {
+                request = request.header("If-None-Match", etag);
+            }
+            request.body(Body::empty()).unwrap()
+        }
+
+        fn assets_config() -> Arc<ProxyConfig> {
+            Arc::new(super::make_config("localhost"))
         }
 
         #[tokio::test]
-        async fn test_logging_in_no_username(){
-            let config = ProxyConfig::from_values(
-                &format!("localhost"),
-                "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
-                ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
-                None, None
-            ).unwrap();
+        async fn test_styles_etag() {
+            let resp = handle(styles_request(None), assets_config()).await;
+            assert_eq!(resp.status(), 200);
+            assert_eq!(resp.headers().get("Cache-Control").unwrap(), "public, max-age=86400");
+            let etag = resp.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+            assert!(etag.starts_with('"') && etag.ends_with('"') && etag.len() == 18);
+            let body = resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await;
+            assert!(!body.is_empty());
 
-            let request = Request::builder()
-                .uri("/".parse::<Uri>().unwrap())
-                .method("POST")
-                .body(Body::from("password=password")).unwrap();
+            let resp = handle(styles_request(Some("\"0000000000000000\"")), assets_config()).await;
+            assert_eq!(resp.status(), 200);
+        }
 
-            let config = Arc::new(config);
-            let resp = handle(request, config.clone()).await;
-            assert_eq!(resp.status(), 303);
-            assert_eq!(resp.headers().get("Location").unwrap(), "/");
-            assert!(resp.headers().get("Set-Cookie").is_some());
+        #[rstest(if_none_match,
+            case(""),
+            case("W/"),
+            case("\"0000000000000000\", "),
+        )]
+        #[tokio::test]
+        async fn test_styles_not_modified(if_none_match: &str) {
+            let resp = handle(styles_request(None), assets_config()).await;
+            let etag = resp.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+
+            let resp = handle(styles_request(Some(&format!("{}{}", if_none_match, etag))), assets_config()).await;
+            assert_eq!(resp.status(), 304);
+            assert_eq!(resp.headers().get("ETag").unwrap(), etag.as_str());
+            let body = resp.into_body()
+                .map(|c| c.unwrap().to_vec())
+                .concat().await;
+            assert!(body.is_empty());
         }
     }
 }
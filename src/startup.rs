@@ -0,0 +1,186 @@
+use std::time::Duration;
+use hyper::{Request, Body, Uri};
+use clap::crate_version;
+use crate::config::ProxyConfig;
+use crate::credentials::CredentialsStore;
+
+
+async fn head_request(config: &ProxyConfig, uri: &Uri) -> Result<(), String> {
+    let request = Request::builder()
+        .method("HEAD")
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+
+    let client = &config.proxy_options().client;
+    match tokio::time::timeout(Duration::from_secs(5), client.request(request)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(error)) => Err(error.to_string()),
+        Err(_) => Err("Request timed out".to_string())
+    }
+}
+
+pub async fn check_upstreams(config: &ProxyConfig) -> Vec<(&Uri, Result<(), String>)> {
+    let checks = config.upstreams().into_iter()
+        .map(|uri| async move { (uri, head_request(config, uri).await) });
+    futures::future::join_all(checks).await
+}
+
+pub async fn warm_connections(config: &ProxyConfig) -> Vec<String> {
+    // Requests are sent at once, so that each of them opens its own connection
+    let requests = config.upstreams().into_iter()
+        .flat_map(|uri| (0..config.warm_connections()).map(move |_| head_request(config, uri)));
+    futures::future::join_all(requests).await.into_iter()
+        .filter_map(Result::err)
+        .collect()
+}
+
+pub fn summary(config: &ProxyConfig, upstream_checks: Option<&[(&Uri, Result<(), String>)]>) -> Vec<String> {
+    let mut lines = vec![
+        format!("TiddlyProxy {}", crate_version!()),
+        format!("Listening on: {}", config.socket_addrs().iter()
+            .map(|addr| format!("http://{}", addr))
+            .collect::<Vec<String>>()
+            .join(", ")),
+        format!("Wiki server: {}", config.remote_uri()),
+        format!(
+            "Users: {} ({})",
+            config.user_count(),
+            if config.requires_username() { "username required" } else { "password only" }
+        )
+    ];
+
+    for (prefix, uri) in config.routes().iter().filter(|(prefix, _)| !prefix.is_empty()) {
+        lines.push(format!("Route: {}/ -> {}", prefix, uri));
+    }
+    if let Some(uri) = config.canary_uri() {
+        lines.push(format!("Canary: {}", uri));
+    }
+
+    for (uri, check) in upstream_checks.unwrap_or_default() {
+        match check {
+            Ok(()) => lines.push(format!("Wiki server {} is reachable", uri)),
+            Err(error) => lines.push(format!("Warning: wiki server {} is not reachable: {}", uri, error))
+        }
+    }
+    lines
+}
+
+
+#[cfg(test)]
+mod tests {
+    use hyper::Uri;
+    use crate::config::ProxyConfig;
+    use super::{check_upstreams, warm_connections, summary};
+
+    fn unused_address() -> String {
+        // Nothing listens on a port that was just released
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_summary_unreachable_upstream() {
+        let address = unused_address();
+        let config = ProxyConfig::from_values(
+            &address,
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            "user1:ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8; \
+             user2:FEDCBA:61aa1f3ae8e8cfafe089ed0c0c115f316e126c27032ef171e89329cb5de67145",
+            None, Some("8080")
+        ).unwrap();
+
+        let checks = check_upstreams(&config).await;
+        assert_eq!(checks.len(), 1);
+        assert!(checks[0].1.is_err());
+
+        let lines = summary(&config, Some(&checks));
+        assert_eq!(lines[1], "Listening on: http://127.0.0.1:8080");
+        assert_eq!(lines[2], format!("Wiki server: http://{}/", address));
+        assert_eq!(lines[3], "Users: 2 (username required)");
+        assert!(lines[4].starts_with(&format!("Warning: wiki server http://{}/ is not reachable", address)));
+    }
+
+    #[tokio::test]
+    async fn test_summary_reachable_upstream() {
+        let mock_server = httpmock::MockServer::start();
+        let uri: Uri = format!("http://{}/", mock_server.address()).parse().unwrap();
+        let config = ProxyConfig::from_values(
+            &uri.to_string(),
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap();
+
+        let checks = check_upstreams(&config).await;
+        assert_eq!(checks, vec![(&uri, Ok(()))]);
+
+        let lines = summary(&config, Some(&checks));
+        assert_eq!(lines[3], "Users: 1 (password only)");
+        assert_eq!(lines[4], format!("Wiki server {} is reachable", uri));
+    }
+
+    #[tokio::test]
+    async fn test_checking_routed_upstreams() {
+        let mock_server = httpmock::MockServer::start();
+        let mock = httpmock::Mock::new()
+            .expect_method(httpmock::Method::HEAD)
+            .create_on(&mock_server);
+        let reachable: Uri = format!("http://{}/", mock_server.address()).parse().unwrap();
+        let unreachable: Uri = format!("http://{}/", unused_address()).parse().unwrap();
+        let config = ProxyConfig::from_values(
+            &reachable.to_string(),
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap().with_routes(vec![
+            ("".to_string(), reachable.clone()),
+            ("/notes".to_string(), unreachable.clone())
+        ]).with_warm_connections(2);
+
+        let checks = check_upstreams(&config).await;
+        assert_eq!(checks.len(), 2);
+        assert!(checks.iter().any(|(uri, check)| **uri == reachable && check.is_ok()));
+        assert!(checks.iter().any(|(uri, check)| **uri == unreachable && check.is_err()));
+
+        assert_eq!(warm_connections(&config).await.len(), 2);
+        assert_eq!(mock.times_called(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_warming_connections() {
+        let mock_server = httpmock::MockServer::start();
+        let mock = httpmock::Mock::new()
+            .expect_method(httpmock::Method::HEAD)
+            .expect_path("/")
+            .create_on(&mock_server);
+        let config = ProxyConfig::from_values(
+            &format!("{}", mock_server.address()),
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap().with_warm_connections(3);
+
+        assert!(warm_connections(&config).await.is_empty());
+        assert_eq!(mock.times_called(), 3);
+
+        let config = ProxyConfig::from_values(
+            &unused_address(),
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap().with_warm_connections(2);
+        assert_eq!(warm_connections(&config).await.len(), 2);
+    }
+
+    #[test]
+    fn test_summary_without_check() {
+        let config = ProxyConfig::from_values(
+            "localhost",
+            "00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF",
+            ":ABCDEF:5ebb11dc077b1ecbf1a226571fecfe15ce48924de7c12c9b478bac660dd816b8",
+            None, None
+        ).unwrap();
+        assert_eq!(summary(&config, None).len(), 4);
+    }
+}